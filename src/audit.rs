@@ -0,0 +1,97 @@
+use rusqlite::{Connection, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{self, Path, PathBuf};
+
+use crate::{for_each_file_record, get_connection_source_filepath, map_path};
+
+// Extensions counted as audio when walking the folders
+const AUDIO_EXTENSIONS: [&str; 13] = ["wav", "bwf", "rf64", "w64", "aif", "aiff", "caf", "flac", "mp3", "m4a", "aac", "ogg", "wv"];
+// Files listed per section unless --verbose is on
+const LISTED_FILES: usize = 10;
+
+// AUDIT
+// The reverse of --orphans: audio files under the roots that no record points at, because they were never
+// scanned or their record was removed by an earlier run.  Optionally splits out the files whose filename is
+// already in the database, as those are most likely duplicates sitting on disk.
+pub fn audit_disk(conn: &Connection, roots: &[String], path_maps: &[(String, String)], flag_known_names: bool, verbose: bool) -> Result<()> {
+    let mut indexed: HashSet<PathBuf> = HashSet::new();
+    let mut names: HashMap<String, usize> = HashMap::new();
+    for_each_file_record(conn, "", |record| {
+        if let Some(filepath) = record.filepath {
+            indexed.insert(PathBuf::from(map_path(&filepath, path_maps)));
+        }
+        if flag_known_names {
+            names.entry(record.filename.to_lowercase()).or_insert(record.id);
+        }
+        Ok(())
+    })?;
+
+    println!("Searching {} for audio files missing from {}", roots.join(", "), get_connection_source_filepath(conn));
+    let mut audio_files = 0;
+    let mut unindexed: Vec<PathBuf> = Vec::new();
+    let mut known: Vec<(PathBuf, usize)> = Vec::new();
+    // Relative roots wouldn't match the absolute paths in the database.  Symlinks aren't resolved, since the database
+    // paths they're compared with aren't either.
+    let mut folders: Vec<PathBuf> = roots.iter().map(|root| path::absolute(root).unwrap_or_else(|_| PathBuf::from(root))).collect();
+    while let Some(folder) = folders.pop() {
+        let Ok(entries) = fs::read_dir(&folder) else {
+            println!("Can't read folder {}", folder.display());
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            // Symlinked folders aren't followed, so a link pointing back up the tree can't loop forever
+            let Ok(file_type) = entry.file_type() else { continue };
+            if file_type.is_dir() {
+                folders.push(path);
+                continue;
+            }
+            if !is_audio_file(&path) {
+                continue;
+            }
+            audio_files += 1;
+            if indexed.contains(&path) {
+                continue;
+            }
+            let known_id = path.file_name().and_then(|name| names.get(&name.to_string_lossy().to_lowercase()));
+            match known_id {
+                Some(id) => known.push((path, *id)),
+                None => unindexed.push(path),
+            }
+        }
+    }
+    unindexed.sort();
+    known.sort();
+
+    let list = |title: &str, files: Vec<String>| {
+        if files.is_empty() {
+            return;
+        }
+        println!("{}:", title);
+        let shown = if verbose { files.len() } else { LISTED_FILES };
+        for file in files.iter().take(shown) {
+            println!("    {}", file);
+        }
+        if files.len() > shown {
+            println!("    ... and {} more (use -v to list them all)", files.len() - shown);
+        }
+    };
+    list("Not in the database", unindexed.iter().map(|path| path.display().to_string()).collect());
+    list(
+        "Not in the database, filename matches a record (likely duplicates)",
+        known.iter().map(|(path, id)| format!("{} (same name as ID {})", path.display(), id)).collect(),
+    );
+
+    println!("{} audio files found, {} not in the database", audio_files, unindexed.len() + known.len());
+    if flag_known_names {
+        println!("{} of them share a filename with a record in the database", known.len());
+    }
+    Ok(())
+}
+
+fn is_audio_file(path: &Path) -> bool {
+    path.extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .is_some_and(|extension| AUDIO_EXTENSIONS.contains(&extension.as_str()))
+}
@@ -0,0 +1,156 @@
+use rusqlite::types::Value;
+use rusqlite::{Connection, Result};
+use std::collections::HashMap;
+use std::error::Error;
+
+use crate::{backup_database, fetch_compare_keys, get_connection_source_filepath, get_data_columns, quote_columns, CompareKey};
+
+// DIFF
+pub fn diff_databases(conn_a: &Connection, conn_b: &Connection, compare_key: CompareKey, path_maps: &[(String, String)], verbose: bool) -> Result<()> {
+    let name_a = get_connection_source_filepath(conn_a);
+    let name_b = get_connection_source_filepath(conn_b);
+    println!("Comparing {} and {} by {}", name_a, name_b, compare_key.describe());
+
+    let keys_a = group_ids_by_key(fetch_compare_keys(conn_a, compare_key, &None, false, path_maps)?);
+    let keys_b = group_ids_by_key(fetch_compare_keys(conn_b, compare_key, &None, false, path_maps)?);
+
+    // Records sharing a key are paired up in rowid order, anything left over is unique to its database
+    let mut only_in_a: Vec<usize> = Vec::new();
+    let mut only_in_b: Vec<usize> = Vec::new();
+    let mut shared: Vec<(usize, usize)> = Vec::new();
+
+    for (key, ids_a) in &keys_a {
+        let ids_b = keys_b.get(key).map(Vec::as_slice).unwrap_or_default();
+        shared.extend(ids_a.iter().copied().zip(ids_b.iter().copied()));
+        only_in_a.extend(ids_a.iter().skip(ids_b.len()));
+    }
+    for (key, ids_b) in &keys_b {
+        let paired = keys_a.get(key).map_or(0, Vec::len);
+        only_in_b.extend(ids_b.iter().skip(paired));
+    }
+    only_in_a.sort();
+    only_in_b.sort();
+    shared.sort();
+
+    let columns = get_shared_columns(conn_a, conn_b)?;
+    let select = format!("SELECT {} FROM justinmetadata WHERE rowid = ?", quote_columns(&columns));
+    let mut stmt_a = conn_a.prepare(&select)?;
+    let mut stmt_b = conn_b.prepare(&select)?;
+
+    let mut column_counts: HashMap<String, usize> = HashMap::new();
+    let mut changed_records = 0;
+
+    for (id_a, id_b) in &shared {
+        let values_a = fetch_values(&mut stmt_a, *id_a, columns.len())?;
+        let values_b = fetch_values(&mut stmt_b, *id_b, columns.len())?;
+
+        let differences: Vec<usize> = (0..columns.len()).filter(|&i| values_a[i] != values_b[i]).collect();
+        if differences.is_empty() {
+            continue;
+        }
+        changed_records += 1;
+
+        if verbose {
+            println!("ID {} / ID {}:", id_a, id_b);
+        }
+        for i in differences {
+            *column_counts.entry(columns[i].clone()).or_insert(0) += 1;
+            if verbose {
+                println!("    {}: {} -> {}", columns[i], display_value(&values_a[i]), display_value(&values_b[i]));
+            }
+        }
+    }
+
+    if verbose {
+        print_records(conn_a, &format!("Only in {}", name_a), &only_in_a)?;
+        print_records(conn_b, &format!("Only in {}", name_b), &only_in_b)?;
+    }
+
+    println!("{} records only in {}", only_in_a.len(), name_a);
+    println!("{} records only in {}", only_in_b.len(), name_b);
+    println!("{} records in both, {} with column differences", shared.len(), changed_records);
+
+    if !column_counts.is_empty() {
+        let mut column_counts: Vec<(String, usize)> = column_counts.into_iter().collect();
+        column_counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        println!("Differences by column:");
+        for (column, count) in column_counts {
+            println!("    {}: {}", column, count);
+        }
+    }
+
+    Ok(())
+}
+
+fn group_ids_by_key(keys: Vec<(usize, String)>) -> HashMap<String, Vec<usize>> {
+    let mut map: HashMap<String, Vec<usize>> = HashMap::new();
+    for (id, key) in keys {
+        map.entry(key).or_default().push(id);
+    }
+    for ids in map.values_mut() {
+        ids.sort();
+    }
+    map
+}
+
+fn fetch_values(stmt: &mut rusqlite::Statement, id: usize, count: usize) -> Result<Vec<Value>> {
+    stmt.query_row([id], |row| (0..count).map(|i| row.get::<_, Value>(i)).collect())
+}
+
+fn display_value(value: &Value) -> String {
+    match value {
+        Value::Null => "NULL".to_string(),
+        Value::Integer(value) => value.to_string(),
+        Value::Real(value) => value.to_string(),
+        Value::Text(text) => format!("'{}'", text),
+        Value::Blob(blob) => format!("<{} byte blob>", blob.len()),
+    }
+}
+
+fn print_records(conn: &Connection, title: &str, ids: &[usize]) -> Result<()> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+    println!("{}:", title);
+    let mut stmt = conn.prepare("SELECT filename, filepath FROM justinmetadata WHERE rowid = ?")?;
+    for id in ids {
+        let (filename, filepath): (String, Option<String>) = stmt.query_row([id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        println!("    ID {}: {} ({})", id, filename, filepath.unwrap_or_default());
+    }
+    Ok(())
+}
+
+
+// MERGE
+// Copies the target database and appends every record from the merge database.
+// Duplicates between them are then removed by the regular passes and order rules.
+pub fn merge_databases(target_db_path: &str, merge_db_path: &str, merged_db_path: &str) -> Result<(), Box<dyn Error>> {
+    println!("Merging {} into a copy of {}", merge_db_path, target_db_path);
+    backup_database(&Connection::open(target_db_path)?, merged_db_path)?;
+
+    let conn = Connection::open(merged_db_path)?;
+    let merge_conn = Connection::open(merge_db_path)?;
+    let columns = quote_columns(&get_shared_columns(&conn, &merge_conn)?);
+    drop(merge_conn);
+
+    conn.execute("ATTACH DATABASE ? AS merge_source", [merge_db_path])?;
+    let inserted = conn.execute(
+        &format!("INSERT INTO main.justinmetadata ({}) SELECT {} FROM merge_source.justinmetadata", columns, columns),
+        [],
+    )?;
+    conn.execute("DETACH DATABASE merge_source", [])?;
+
+    println!("Added {} records from {} to {}", inserted, merge_db_path, merged_db_path);
+    Ok(())
+}
+
+
+// Columns present in both databases, leaving out the INTEGER PRIMARY KEY which is just the rowid
+fn get_shared_columns(conn_a: &Connection, conn_b: &Connection) -> Result<Vec<String>> {
+    let columns_b = get_data_columns(conn_b)?;
+    let columns = get_data_columns(conn_a)?
+        .into_iter()
+        .filter(|column| columns_b.iter().any(|other| other.eq_ignore_ascii_case(column)))
+        .collect();
+    Ok(columns)
+}
@@ -0,0 +1,328 @@
+use std::collections::BTreeMap;
+
+// Ids sharing their high bits live in one chunk of 65536 ids
+const CHUNK_BITS: usize = 16;
+const CHUNK_WORDS: usize = (1 << CHUNK_BITS) / 64;
+// A chunk holding more ids than this is smaller as a bitmap than as a sorted list
+const ARRAY_LIMIT: usize = 4096;
+
+// A set of justinmetadata rowids, stored in chunks like a roaring bitmap.
+// Rowids are usually dense in a Soundminer database, so most chunks are bitmaps with one bit per rowid and combining
+// the results of the searches is a word-wise OR.  Sparse chunks are sorted lists, so a stray huge rowid costs a few bytes.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RowIdSet {
+    chunks: BTreeMap<usize, Chunk>,
+    len: usize,
+}
+
+// A chunk is a bitmap exactly when it holds more than ARRAY_LIMIT ids, so equal sets have equal chunks
+#[derive(Clone, Debug, PartialEq)]
+enum Chunk {
+    Array(Vec<u16>),
+    Bitmap { words: Box<[u64; CHUNK_WORDS]>, len: usize },
+}
+
+impl RowIdSet {
+    pub fn new() -> RowIdSet {
+        RowIdSet::default()
+    }
+
+    // Returns true when the id wasn't in the set yet
+    pub fn insert(&mut self, id: usize) -> bool {
+        let (key, low) = split(id);
+        let chunk = self.chunks.entry(key).or_insert_with(|| Chunk::Array(Vec::new()));
+        if !chunk.insert(low) {
+            return false;
+        }
+        self.len += 1;
+        true
+    }
+
+    pub fn remove(&mut self, id: usize) -> bool {
+        let (key, low) = split(id);
+        let Some(chunk) = self.chunks.get_mut(&key) else { return false };
+        if !chunk.remove(low) {
+            return false;
+        }
+        if chunk.is_empty() {
+            self.chunks.remove(&key);
+        }
+        self.len -= 1;
+        true
+    }
+
+    pub fn contains(&self, id: usize) -> bool {
+        let (key, low) = split(id);
+        self.chunks.get(&key).is_some_and(|chunk| chunk.contains(low))
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn union_with(&mut self, other: &RowIdSet) {
+        for (key, other_chunk) in &other.chunks {
+            match self.chunks.get_mut(key) {
+                Some(chunk) => {
+                    self.len -= chunk.len();
+                    chunk.union_with(other_chunk);
+                    self.len += chunk.len();
+                }
+                None => {
+                    self.chunks.insert(*key, other_chunk.clone());
+                    self.len += other_chunk.len();
+                }
+            }
+        }
+    }
+
+    // Ids in ascending order, .rev() gives them in descending order
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = usize> + '_ {
+        self.chunks
+            .iter()
+            .flat_map(|(&key, chunk)| chunk.iter().map(move |low| (key << CHUNK_BITS) | low))
+    }
+}
+
+fn split(id: usize) -> (usize, u16) {
+    (id >> CHUNK_BITS, (id & ((1 << CHUNK_BITS) - 1)) as u16)
+}
+
+impl Chunk {
+    fn insert(&mut self, low: u16) -> bool {
+        match self {
+            Chunk::Array(ids) => match ids.binary_search(&low) {
+                Ok(_) => false,
+                Err(index) => {
+                    ids.insert(index, low);
+                    if ids.len() > ARRAY_LIMIT {
+                        *self = Chunk::Bitmap { words: to_bitmap(ids), len: ids.len() };
+                    }
+                    true
+                }
+            },
+            Chunk::Bitmap { words, len } => {
+                let (word, bit) = (low as usize / 64, 1u64 << (low % 64));
+                if words[word] & bit != 0 {
+                    return false;
+                }
+                words[word] |= bit;
+                *len += 1;
+                true
+            }
+        }
+    }
+
+    fn remove(&mut self, low: u16) -> bool {
+        match self {
+            Chunk::Array(ids) => match ids.binary_search(&low) {
+                Ok(index) => {
+                    ids.remove(index);
+                    true
+                }
+                Err(_) => false,
+            },
+            Chunk::Bitmap { words, len } => {
+                let (word, bit) = (low as usize / 64, 1u64 << (low % 64));
+                if words[word] & bit == 0 {
+                    return false;
+                }
+                words[word] &= !bit;
+                *len -= 1;
+                if *len <= ARRAY_LIMIT {
+                    *self = Chunk::Array(self.iter().map(|low| low as u16).collect());
+                }
+                true
+            }
+        }
+    }
+
+    fn contains(&self, low: u16) -> bool {
+        match self {
+            Chunk::Array(ids) => ids.binary_search(&low).is_ok(),
+            Chunk::Bitmap { words, .. } => words[low as usize / 64] & (1u64 << (low % 64)) != 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Chunk::Array(ids) => ids.len(),
+            Chunk::Bitmap { len, .. } => *len,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn union_with(&mut self, other: &Chunk) {
+        match (&mut *self, other) {
+            (Chunk::Bitmap { words, len }, Chunk::Bitmap { words: other_words, .. }) => {
+                for (word, other_word) in words.iter_mut().zip(other_words.iter()) {
+                    *word |= other_word;
+                }
+                *len = words.iter().map(|word| word.count_ones() as usize).sum();
+            }
+            (Chunk::Bitmap { .. }, Chunk::Array(other_ids)) => {
+                for &low in other_ids {
+                    self.insert(low);
+                }
+            }
+            (Chunk::Array(ids), _) => {
+                let mut merged = other.clone();
+                for &low in ids.iter() {
+                    merged.insert(low);
+                }
+                *self = merged;
+            }
+        }
+    }
+
+    // The low bits of the ids in the chunk, in ascending order
+    fn iter(&self) -> Box<dyn DoubleEndedIterator<Item = usize> + '_> {
+        match self {
+            Chunk::Array(ids) => Box::new(ids.iter().map(|&low| low as usize)),
+            Chunk::Bitmap { words, .. } => Box::new(
+                words
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(index, &word)| Bits { base: index * 64, word }),
+            ),
+        }
+    }
+}
+
+fn to_bitmap(ids: &[u16]) -> Box<[u64; CHUNK_WORDS]> {
+    let mut words = Box::new([0u64; CHUNK_WORDS]);
+    for &low in ids {
+        words[low as usize / 64] |= 1u64 << (low % 64);
+    }
+    words
+}
+
+impl FromIterator<usize> for RowIdSet {
+    fn from_iter<I: IntoIterator<Item = usize>>(ids: I) -> RowIdSet {
+        let mut set = RowIdSet::new();
+        set.extend(ids);
+        set
+    }
+}
+
+impl Extend<usize> for RowIdSet {
+    fn extend<I: IntoIterator<Item = usize>>(&mut self, ids: I) {
+        for id in ids {
+            self.insert(id);
+        }
+    }
+}
+
+// The set bits of a single word
+struct Bits {
+    base: usize,
+    word: u64,
+}
+
+impl Iterator for Bits {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if self.word == 0 {
+            return None;
+        }
+        let bit = self.word.trailing_zeros() as usize;
+        self.word &= self.word - 1;
+        Some(self.base + bit)
+    }
+}
+
+impl DoubleEndedIterator for Bits {
+    fn next_back(&mut self) -> Option<usize> {
+        if self.word == 0 {
+            return None;
+        }
+        let bit = 63 - self.word.leading_zeros() as usize;
+        self.word &= !(1u64 << bit);
+        Some(self.base + bit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_and_remove() {
+        let mut set = RowIdSet::new();
+        assert!(set.insert(5));
+        assert!(!set.insert(5));
+        assert!(set.insert(70_000));
+        assert_eq!(set.len(), 2);
+        assert!(set.contains(5) && set.contains(70_000) && !set.contains(6));
+
+        assert!(set.remove(5));
+        assert!(!set.remove(5));
+        assert!(!set.remove(123));
+        assert_eq!(set.len(), 1);
+        assert!(!set.contains(5));
+        assert!(set.remove(70_000));
+        assert!(set.is_empty());
+        assert_eq!(set, RowIdSet::new());
+    }
+
+    #[test]
+    fn huge_rowids_stay_small() {
+        let mut set = RowIdSet::new();
+        set.insert(1);
+        set.insert(4_000_000_000_000);
+        assert_eq!(set.len(), 2);
+        assert!(set.contains(4_000_000_000_000));
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![1, 4_000_000_000_000]);
+    }
+
+    #[test]
+    fn dense_chunks_switch_to_bitmaps_and_back() {
+        let mut set: RowIdSet = (0..10_000).collect();
+        assert!(matches!(set.chunks[&0], Chunk::Bitmap { .. }));
+        assert_eq!(set.len(), 10_000);
+        for id in 0..9_000 {
+            assert!(set.remove(id));
+        }
+        assert!(matches!(set.chunks[&0], Chunk::Array(_)));
+        assert_eq!(set.iter().collect::<Vec<_>>(), (9_000..10_000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn iterates_in_both_directions() {
+        let ids = [3, 64, 65, 65_535, 65_536, 200_000, 1 << 40];
+        let mut set: RowIdSet = ids.iter().copied().collect();
+        assert_eq!(set.iter().collect::<Vec<_>>(), ids);
+        assert_eq!(set.iter().rev().collect::<Vec<_>>(), ids.iter().rev().copied().collect::<Vec<_>>());
+
+        set.extend(0..5_000);
+        let mut expected: Vec<usize> = (0..5_000).chain([65_535, 65_536, 200_000, 1 << 40]).collect();
+        assert_eq!(set.iter().collect::<Vec<_>>(), expected);
+        expected.reverse();
+        assert_eq!(set.iter().rev().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn union_with_mixed_chunks() {
+        let mut sparse: RowIdSet = [1, 2, 100_000, 1 << 33].into_iter().collect();
+        let dense: RowIdSet = (0..6_000).chain([100_001]).collect();
+        sparse.union_with(&dense);
+        assert_eq!(sparse.len(), 6_000 + 3);
+        assert!(sparse.contains(5_999) && sparse.contains(100_000) && sparse.contains(100_001) && sparse.contains(1 << 33));
+
+        let mut dense_copy = dense.clone();
+        dense_copy.union_with(&[3, 7_000, 1 << 33].into_iter().collect());
+        assert_eq!(dense_copy.len(), dense.len() + 2);
+
+        let mut empty = RowIdSet::new();
+        empty.union_with(&sparse);
+        assert_eq!(empty, sparse);
+    }
+}
@@ -1,20 +1,39 @@
 #![allow(non_snake_case)]
-use rusqlite::{Connection, Result};
+use rusqlite::{Connection, DatabaseName, ErrorCode, OpenFlags, Result};
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::ValueRef;
 use std::collections::HashSet;
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::env;
 use std::fs::{self, File};
 use std::io::{self, BufRead, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 use std::error::Error;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 // use terminal_size::{Width, terminal_size};
+use aho_corasick::AhoCorasick;
 use regex::Regex;
+use sha2::{Digest, Sha256};
+
+mod audit;
+mod diff;
+mod idset;
+mod report;
+mod state;
+
+use idset::RowIdSet;
+use state::WorkState;
 // use ordered_float::OrderedFloat;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 const BATCH_SIZE: usize = 12321;
 
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
 const DEFAULT_ORDER: [&str; 6] = [
     "duration DESC",
     "channels DESC",
@@ -73,28 +92,369 @@ const DEFAULT_TAGS: [&str; 46] = [
     "-ZXN5_", 
 ];
 
+// Pro Tools multi-mono channel suffixes, e.g. Amb.L.wav, Amb.R.wav, Amb.C.wav
+const CHANNEL_SUFFIXES: [&str; 20] = [
+    "L", "R", "C", "Ls", "Rs", "Lss", "Rss", "Lsr", "Rsr", "Lrs", "Rrs",
+    "Lc", "Rc", "Cs", "Lf", "LFE", "Lfe", "Lw", "Rw", "S",
+];
+
+const DEFAULT_METADATA_COLUMNS: [&str; 5] = [
+    "Description",
+    "Keywords",
+    "Category",
+    "SubCategory",
+    "Notes",
+];
+
+const DEFAULT_NAME_TEMPLATE: &str = "{name}_{suffix}{ext}";
+const DEFAULT_KEEP_BACKUPS: usize = 5;
+
 const ORDER_FILE_PATH: &str = "SMDupe_Order.txt";
 const TAG_FILE_PATH: &str = "SMDupe_tags.txt";
+const METADATA_FILE_PATH: &str = "SMDupe_metadata.txt";
+const PROTECT_FILE_PATH: &str = "SMDupe_protect.txt";
+const PATHS_FILE_PATH: &str = "SMDupe_paths.txt";
 
 
 
-#[derive(Hash, Eq, PartialEq, Clone, Debug)]
+// A justinmetadata row with its audio properties parsed.  Records are identified by rowid alone,
+// the other fields are whatever the database holds (None when a value is missing or unreadable).
+#[derive(Clone, Debug)]
 struct FileRecord {
     id: usize,
     filename: String,
-    duration: String,
+    pathname: Option<String>,
+    duration: Option<Duration>,
+    sample_rate: Option<u32>,
+    bit_depth: Option<u32>,
+    channels: Option<u32>,
+    library: Option<String>,
+    show: Option<String>,
+    filepath: Option<String>,
+    size: Option<u64>,
+}
+
+impl PartialEq for FileRecord {
+    fn eq(&self, other: &FileRecord) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for FileRecord {}
+
+impl Hash for FileRecord {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+impl FileRecord {
+    // Short summary of the audio properties, e.g. 00:01:02.500 48kHz 24-bit 2ch LIB1 / ShowB
+    fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(duration) = self.duration {
+            let millis = duration.as_millis();
+            parts.push(format!("{:02}:{:02}:{:02}.{:03}", millis / 3_600_000, millis / 60_000 % 60, millis / 1000 % 60, millis % 1000));
+        }
+        if let Some(sample_rate) = self.sample_rate {
+            parts.push(format!("{}kHz", sample_rate as f64 / 1000.0));
+        }
+        if let Some(bit_depth) = self.bit_depth {
+            parts.push(format!("{}-bit", bit_depth));
+        }
+        if let Some(channels) = self.channels {
+            parts.push(format!("{}ch", channels));
+        }
+        let source: Vec<&str> = [&self.library, &self.show].into_iter().flatten().map(String::as_str).collect();
+        if !source.is_empty() {
+            parts.push(source.join(" / "));
+        }
+        parts.join(" ")
+    }
+}
+
+// Columns read into a FileRecord, in order after the rowid
+const FILE_RECORD_COLUMNS: [&str; 9] = ["filename", "pathname", "duration", "sampleRate", "bitDepth", "channels", "library", "show", "filepath"];
+// The file size column goes by different names, the first one found is read after the columns above
+const SIZE_COLUMNS: [&str; 2] = ["FileSize", "Size"];
+
+// How records are matched between the target and comparison databases
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CompareKey {
+    Filename,
+    Duration,
+    Format,
+    Normalized,
+    Hash,
+    Path,
+}
+
+impl CompareKey {
+    fn parse(key: &str) -> Option<CompareKey> {
+        match key.to_lowercase().as_str() {
+            "filename" => Some(CompareKey::Filename),
+            "duration" => Some(CompareKey::Duration),
+            "format" => Some(CompareKey::Format),
+            "normalized" => Some(CompareKey::Normalized),
+            "hash" => Some(CompareKey::Hash),
+            "path" => Some(CompareKey::Path),
+            _ => None,
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            CompareKey::Filename => "filename",
+            CompareKey::Duration => "filename and duration",
+            CompareKey::Format => "filename, sample rate and channels",
+            CompareKey::Normalized => "normalized filename",
+            CompareKey::Hash => "file content hash",
+            CompareKey::Path => "file path",
+        }
+    }
+}
+
+// What happens when both the keeper and a removed duplicate have a value for a metadata column
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MetadataPolicy {
+    Keep,
+    Concatenate,
+    Longest,
+}
+
+impl MetadataPolicy {
+    fn parse(policy: &str) -> Option<MetadataPolicy> {
+        match policy.to_lowercase().as_str() {
+            "keep" => Some(MetadataPolicy::Keep),
+            "concat" | "concatenate" => Some(MetadataPolicy::Concatenate),
+            "longest" | "prefer-longest" => Some(MetadataPolicy::Longest),
+            _ => None,
+        }
+    }
+}
+
+// How far apart the durations of two same-name records can be and still count as the same sound
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DurationTolerance {
+    Seconds(f64),
+    Percent(f64),
+}
+
+impl DurationTolerance {
+    // 0.5 is half a second either way, 10% is ten percent of the shorter duration
+    fn parse(tolerance: &str) -> Option<DurationTolerance> {
+        let tolerance = tolerance.trim();
+        let (value, percent) = match tolerance.strip_suffix('%') {
+            Some(value) => (value, true),
+            None => (tolerance.strip_suffix('s').unwrap_or(tolerance), false),
+        };
+        let value: f64 = value.trim().parse().ok()?;
+        if !value.is_finite() || value < 0.0 {
+            return None;
+        }
+        Some(if percent { DurationTolerance::Percent(value) } else { DurationTolerance::Seconds(value) })
+    }
+
+    fn allows(&self, shorter: Duration, longer: Duration) -> bool {
+        let difference = (longer - shorter).as_secs_f64();
+        match self {
+            DurationTolerance::Seconds(seconds) => difference <= *seconds,
+            DurationTolerance::Percent(percent) => difference <= shorter.as_secs_f64() * percent / 100.0,
+        }
+    }
+}
+
+// A safety limit on how much a run may delete, as a number of records or a percentage
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Limit {
+    Count(usize),
+    Percent(f64),
+}
+
+impl Limit {
+    fn parse(limit: &str) -> Option<Limit> {
+        let limit = limit.trim();
+        match limit.strip_suffix('%') {
+            Some(percent) => percent.trim().parse().ok().filter(|percent: &f64| percent.is_finite() && *percent >= 0.0).map(Limit::Percent),
+            None => limit.parse().ok().map(Limit::Count),
+        }
+    }
+
+    fn exceeded(&self, count: usize, total: usize) -> bool {
+        match self {
+            Limit::Count(max) => count > *max,
+            Limit::Percent(percent) => count as f64 > total as f64 * percent / 100.0,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Limit::Count(max) => format!("{} records", max),
+            Limit::Percent(percent) => format!("{}%", percent),
+        }
+    }
+}
+
+// A never-delete rule from SMDupe_protect.txt, written as kind:value
+#[derive(Clone, Debug, PartialEq)]
+enum ProtectRule {
+    Path(String),
+    Library(String),
+    Show(String),
+    Sql(String),
+}
+
+impl ProtectRule {
+    fn parse(line: &str) -> Option<ProtectRule> {
+        let (kind, value) = line.split_once(':')?;
+        let value = value.trim().to_string();
+        if value.is_empty() {
+            return None;
+        }
+        match kind.trim().to_lowercase().as_str() {
+            "path" => Some(ProtectRule::Path(value)),
+            "library" => Some(ProtectRule::Library(value)),
+            "show" => Some(ProtectRule::Show(value)),
+            "sql" => Some(ProtectRule::Sql(value)),
+            _ => None,
+        }
+    }
+
+    // The SQL predicate matching protected records and the value it's bound to.
+    // Paths match anywhere in the file path, or as a glob over the whole path when they use * or ?
+    fn condition(&self) -> (String, Option<&str>) {
+        match self {
+            ProtectRule::Path(path) if path.contains(['*', '?']) => ("filepath GLOB ?".to_string(), Some(path)),
+            ProtectRule::Path(path) => ("instr(filepath, ?) > 0".to_string(), Some(path)),
+            ProtectRule::Library(library) => ("library = ? COLLATE NOCASE".to_string(), Some(library)),
+            ProtectRule::Show(show) => ("show = ? COLLATE NOCASE".to_string(), Some(show)),
+            ProtectRule::Sql(predicate) => (format!("({})", predicate), None),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            ProtectRule::Path(path) => format!("path:{}", path),
+            ProtectRule::Library(library) => format!("library:{}", library),
+            ProtectRule::Show(show) => format!("show:{}", show),
+            ProtectRule::Sql(predicate) => format!("sql:{}", predicate),
+        }
+    }
+}
+
+// The searches a run can make, in the order their results are attributed
+#[derive(Clone, Debug)]
+enum Pass {
+    Compare { path: String, reference: String },
+    Filename,
+    Tags,
+    DeepDive,
+    Orphans,
+}
+
+impl Pass {
+    fn name(&self) -> String {
+        match self {
+            Pass::Compare { path, .. } => format!("compare {}", path),
+            Pass::Filename => "filename".to_string(),
+            Pass::Tags => "tags".to_string(),
+            Pass::DeepDive => "deep dive".to_string(),
+            Pass::Orphans => "orphans".to_string(),
+        }
+    }
+
+    fn reason(&self) -> String {
+        match self {
+            Pass::Compare { reference, .. } => format!("found in {}", reference),
+            Pass::Filename => "duplicate filename".to_string(),
+            Pass::Tags => "filename contains a tag".to_string(),
+            Pass::DeepDive => "deep dive".to_string(),
+            Pass::Orphans => "file missing on disk".to_string(),
+        }
+    }
+}
+
+// Everything that changes what the searches mark, one setting per line.  A resumed run must match the run it continues,
+// otherwise the restored search results would be stale.
+fn get_search_settings(config: &Config) -> Result<String, Box<dyn Error>> {
+    let settings = [
+        format!(
+            "searches: compare {:?}, filename {}, tags {}, deep dive {}, orphans {}, relocate {:?}",
+            config.compare_dbs, config.filename_check, config.prune_tags, config.numbers_check, config.orphans, config.relocate_roots
+        ),
+        format!("order: {}", get_order(ORDER_FILE_PATH)?.join(", ")),
+        format!("tags: {}", if config.prune_tags { get_tags(TAG_FILE_PATH)?.join(", ") } else { String::new() }),
+        format!("compare key: {:?}", config.compare_key),
+        format!("group: {:?}, null: {}, all searches: {}", config.group_sort, config.group_null, config.group_all),
+        format!("duration tolerance: {:?}", config.duration_tolerance),
+        format!("path mappings: {:?}", config.path_maps),
+    ];
+    Ok(settings.join("\n"))
+}
+
+// Which search marked each record, a record marked by several searches is attributed to the first.
+// One set per search rather than a reason per record keeps the memory used down to the bitmaps.
+#[derive(Default)]
+struct Reasons {
+    passes: Vec<(String, RowIdSet)>,
+}
+
+impl Reasons {
+    fn add(&mut self, reason: String, records: RowIdSet) {
+        self.passes.push((reason, records));
+    }
+
+    fn get(&self, id: usize) -> Option<&str> {
+        self.passes.iter().find(|(_, records)| records.contains(id)).map(|(reason, _)| reason.as_str())
+    }
+}
+
+// Records a search marked, the keepers it chose and how long it took (None when restored with --resume).
+// Keepers are checkpointed with the search and looked up in the run state after that.
+struct PassResult {
+    records: RowIdSet,
+    keepers: HashMap<usize, usize>,
+    elapsed: Option<Duration>,
 }
 
 #[derive(Debug)]
 struct Config {
     target_db: Option<String>,
-    compare_db: Option<String>,
+    compare_dbs: Vec<String>,
+    compare_key: Option<CompareKey>,
+    diff_db: Option<String>,
+    merge_db: Option<String>,
     duplicate_db: bool,
+    dupes_note_column: Option<String>,
+    output: Option<String>,
+    dupes_output: Option<String>,
+    name_template: String,
+    force: bool,
+    keep_backups: usize,
+    resume: bool,
+    parallel: bool,
     filename_check: bool,
+    duration_tolerance: Option<DurationTolerance>,
+    max_delete: Option<Limit>,
+    report: Option<String>,
+    file_check: bool,
+    max_per_library: Option<Limit>,
+    max_per_show: Option<Limit>,
+    max_per_pass: Option<Limit>,
+    ignore_limits: bool,
     group_sort: Option<String>,
     group_null: bool,
+    group_all: bool,
     numbers_check: bool,
+    orphans: bool,
+    relocate_roots: Vec<String>,
+    path_maps: Vec<(String, String)>,
+    rewrite_paths: bool,
+    audit_roots: Vec<String>,
+    flag_known_names: bool,
     prune_tags: bool,
+    transfer_metadata: bool,
+    metadata_policy: MetadataPolicy,
     safe: bool,
     prompt: bool,
     verbose: bool,
@@ -103,13 +463,41 @@ struct Config {
 impl Config {
     fn new(args: &[String]) -> Result<Config, &'static str> {
         let mut target_db = None;
-        let mut compare_db: Option<String> = None;
+        let mut compare_dbs: Vec<String> = Vec::new();
+        let mut compare_key: Option<CompareKey> = None;
+        let mut diff_db: Option<String> = None;
+        let mut merge_db: Option<String> = None;
         let mut duplicate_db = false;
+        let mut dupes_note_column: Option<String> = None;
+        let mut output: Option<String> = None;
+        let mut dupes_output: Option<String> = None;
+        let mut name_template = DEFAULT_NAME_TEMPLATE.to_string();
+        let mut force = false;
+        let mut keep_backups = DEFAULT_KEEP_BACKUPS;
+        let mut resume = false;
+        let mut parallel = false;
+        let mut duration_tolerance: Option<DurationTolerance> = None;
+        let mut max_delete: Option<Limit> = None;
+        let mut report: Option<String> = None;
+        let mut file_check = true;
+        let mut max_per_library: Option<Limit> = None;
+        let mut max_per_show: Option<Limit> = None;
+        let mut max_per_pass: Option<Limit> = None;
+        let mut ignore_limits = false;
         let mut filename_check = true;
         let mut group_sort: Option<String> = None;
         let mut group_null = false;
+        let mut group_all = false;
         let mut numbers_check = false;
+        let mut orphans = false;
+        let mut relocate_roots: Vec<String> = Vec::new();
+        let mut path_maps: Vec<(String, String)> = Vec::new();
+        let mut rewrite_paths = false;
+        let mut audit_roots: Vec<String> = Vec::new();
+        let mut flag_known_names = false;
         let mut prune_tags = false;
+        let mut transfer_metadata = false;
+        let mut metadata_policy = MetadataPolicy::Keep;
         let mut safe = true;
         let mut prompt = true;
         let mut verbose = false;
@@ -128,6 +516,43 @@ impl Config {
                 }
                 "--prune-tags" => prune_tags = true,
                 "--deep-dive" => numbers_check = true,
+                "--orphans" => orphans = true,
+                "--map-path" => {
+                    if i + 1 < args.len() {
+                        match parse_path_map(&args[i + 1]) {
+                            Some(map) => path_maps.push(map),
+                            None => {
+                                print_help();
+                                return Err("--map-path needs from=to, e.g. /Volumes/SFX=/mnt/sfx");
+                            }
+                        }
+                        i += 1;
+                    } else {
+                        print_help();
+                        return Err("Missing mapping for --map-path");
+                    }
+                },
+                "--rewrite-paths" => rewrite_paths = true,
+                "--audit-disk" => {
+                    if i + 1 < args.len() {
+                        audit_roots.push(args[i + 1].clone());
+                        i += 1;
+                    } else {
+                        print_help();
+                        return Err("Missing folder for --audit-disk");
+                    }
+                },
+                "--flag-known-names" => flag_known_names = true,
+                "--relocate" => {
+                    if i + 1 < args.len() {
+                        relocate_roots.push(args[i + 1].clone());
+                        orphans = true;
+                        i += 1;
+                    } else {
+                        print_help();
+                        return Err("Missing folder for --relocate");
+                    }
+                },
                 "--no-filename-check" => filename_check = false,
                 "--group-by-show" | "-s" => group_sort = Some("show".to_string()),
                 "--group-by-library" | "-l" => group_sort = Some("library".to_string()),
@@ -150,21 +575,170 @@ impl Config {
                         return Err("group argument missing");
                     }
                 },
+                "--group-all" => group_all = true,
                 "--compare" => {
                     if i + 1 < args.len() {
-                        compare_db = check_path(args[i + 1].as_str());
+                        compare_dbs.extend(get_compare_paths(args[i + 1].as_str()));
                         i += 1; // Skip the next argument since it's the database name
                     } else {
                         print_help();
                         return Err("Missing database name for --compare");
                     }
                 },
+                "--compare-key" => {
+                    if i + 1 < args.len() {
+                        compare_key = match CompareKey::parse(&args[i + 1]) {
+                            Some(key) => Some(key),
+                            None => {
+                                print_help();
+                                return Err("Unknown compare key");
+                            }
+                        };
+                        i += 1;
+                    } else {
+                        print_help();
+                        return Err("Missing key for --compare-key");
+                    }
+                },
+                "--diff" => {
+                    if i + 1 < args.len() {
+                        diff_db = check_path(args[i + 1].as_str());
+                        i += 1; // Skip the next argument since it's the database name
+                        if diff_db.is_none() {
+                            return Err("Database for --diff not found");
+                        }
+                    } else {
+                        print_help();
+                        return Err("Missing database name for --diff");
+                    }
+                },
+                "--merge" => {
+                    if i + 1 < args.len() {
+                        merge_db = check_path(args[i + 1].as_str());
+                        i += 1; // Skip the next argument since it's the database name
+                        if merge_db.is_none() {
+                            return Err("Database for --merge not found");
+                        }
+                    } else {
+                        print_help();
+                        return Err("Missing database name for --merge");
+                    }
+                },
+                "--transfer-metadata" => transfer_metadata = true,
+                "--metadata-policy" => {
+                    if i + 1 < args.len() {
+                        metadata_policy = match MetadataPolicy::parse(&args[i + 1]) {
+                            Some(policy) => policy,
+                            None => {
+                                print_help();
+                                return Err("Unknown metadata policy");
+                            }
+                        };
+                        transfer_metadata = true;
+                        i += 1;
+                    } else {
+                        print_help();
+                        return Err("Missing policy for --metadata-policy");
+                    }
+                },
+                "--output" | "-o" | "--dupes-output" | "--name-template" => {
+                    if i + 1 < args.len() {
+                        let value = Some(args[i + 1].clone());
+                        match args[i].as_str() {
+                            "--output" | "-o" => output = value,
+                            "--dupes-output" => {
+                                dupes_output = value;
+                                duplicate_db = true;
+                            }
+                            _ => name_template = args[i + 1].clone(),
+                        }
+                        i += 1;
+                    } else {
+                        print_help();
+                        return Err("Missing path for output option");
+                    }
+                },
+                "--force" => force = true,
+                "--resume" => resume = true,
+                "--parallel" => parallel = true,
+                "--duration-tolerance" => {
+                    if i + 1 < args.len() {
+                        duration_tolerance = match DurationTolerance::parse(&args[i + 1]) {
+                            Some(tolerance) => Some(tolerance),
+                            None => {
+                                print_help();
+                                return Err("--duration-tolerance needs seconds (0.5) or a percentage (10%)");
+                            }
+                        };
+                        i += 1;
+                    } else {
+                        print_help();
+                        return Err("Missing tolerance for --duration-tolerance");
+                    }
+                },
+                "--max-delete" | "--max-per-library" | "--max-per-show" | "--max-per-pass" => {
+                    if i + 1 < args.len() {
+                        let limit = match Limit::parse(&args[i + 1]) {
+                            Some(limit) => Some(limit),
+                            None => {
+                                print_help();
+                                return Err("Safety limits need a number of records (500) or a percentage (10%)");
+                            }
+                        };
+                        match args[i].as_str() {
+                            "--max-delete" => max_delete = limit,
+                            "--max-per-library" => max_per_library = limit,
+                            "--max-per-show" => max_per_show = limit,
+                            _ => max_per_pass = limit,
+                        }
+                        i += 1;
+                    } else {
+                        print_help();
+                        return Err("Missing limit for safety limit option");
+                    }
+                },
+                "--ignore-limits" => ignore_limits = true,
+                "--no-file-check" => file_check = false,
+                "--report" => {
+                    if i + 1 < args.len() {
+                        report = Some(args[i + 1].clone());
+                        i += 1;
+                    } else {
+                        print_help();
+                        return Err("Missing path for --report");
+                    }
+                },
+                "--keep-backups" => {
+                    if i + 1 < args.len() {
+                        keep_backups = match args[i + 1].parse() {
+                            Ok(count) => count,
+                            Err(_) => {
+                                print_help();
+                                return Err("--keep-backups needs a number");
+                            }
+                        };
+                        i += 1;
+                    } else {
+                        print_help();
+                        return Err("Missing number for --keep-backups");
+                    }
+                },
                 "--no-prompt" | "--yes" => prompt = false,
                 "--unsafe" => {
                     safe = false;
                     prompt = false;
                 },
                 "--create-duplicates-database" => duplicate_db = true,
+                "--dupes-note-column" => {
+                    if i + 1 < args.len() {
+                        dupes_note_column = Some(args[i + 1].clone());
+                        duplicate_db = true;
+                        i += 1;
+                    } else {
+                        print_help();
+                        return Err("Missing column for --dupes-note-column");
+                    }
+                },
                 "--verbose" => verbose = true,
                 "--help" => {
                     print_help();
@@ -197,6 +771,9 @@ impl Config {
                                     }
                                 },
                                 'i' => group_null = true,
+                                'f' => force = true,
+                                'm' => transfer_metadata = true,
+                                'G' => group_all = true,
                                 't' => prune_tags = true,
                                 'n' => filename_check = false,
                                 's' => group_sort = Some("show".to_string()),
@@ -214,7 +791,7 @@ impl Config {
                                 },
                                 'c' => {
                                     if i + 1 < args.len() {
-                                        compare_db = check_path(args[i + 1].as_str());
+                                        compare_dbs.extend(get_compare_paths(args[i + 1].as_str()));
                                         i += 1; // Skip the next argument since it's the database name
                                     } else {
                                         print_help();
@@ -248,15 +825,57 @@ impl Config {
             return Err("No Primary Database Specified");
         }
 
+        // A folder of comparison databases should never compare the target against itself
+        if let Some(target) = &target_db {
+            compare_dbs.retain(|path| !is_same_file(path, target));
+        }
+
+        if resume && merge_db.is_some() {
+            return Err("--resume can't be used with --merge");
+        }
+
+        if group_all && group_sort.is_none() {
+            print_help();
+            return Err("--group-all requires a group column (-g, -s or -l)");
+        }
+
         Ok(Config {
             target_db,
-            compare_db,
+            compare_dbs,
+            compare_key,
+            diff_db,
+            merge_db,
             duplicate_db,
+            dupes_note_column,
+            output,
+            dupes_output,
+            name_template,
+            force,
+            keep_backups,
+            resume,
+            parallel,
             filename_check,
+            duration_tolerance,
+            max_delete,
+            report,
+            file_check,
+            max_per_library,
+            max_per_show,
+            max_per_pass,
+            ignore_limits,
             group_sort,
             group_null,
+            group_all,
             numbers_check,
+            orphans,
+            relocate_roots,
+            path_maps,
+            rewrite_paths,
+            audit_roots,
+            flag_known_names,
             prune_tags,
+            transfer_metadata,
+            metadata_policy,
             safe,
             prompt,
             verbose,
@@ -272,7 +891,36 @@ fn check_path(path: &str) -> Option<String> {
     }
 
 }
- 
+
+// A directory expands to every .sqlite database inside it
+fn get_compare_paths(path: &str) -> Vec<String> {
+    let path_buf = Path::new(path);
+    if !path_buf.is_dir() {
+        return check_path(path).into_iter().collect();
+    }
+
+    let mut paths: Vec<String> = match fs::read_dir(path_buf) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|entry| entry.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("sqlite")))
+            .map(|entry| entry.display().to_string())
+            .collect(),
+        Err(e) => {
+            println!("Unable to read comparison folder {}: {}", path, e);
+            Vec::new()
+        }
+    };
+    paths.sort();
+    paths
+}
+
+fn is_same_file(a: &str, b: &str) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
 
 // GET FUNCTIONS
 fn get_order(file_path: &str) -> Result<Vec<String>, io::Error> {
@@ -282,8 +930,10 @@ fn get_order(file_path: &str) -> Result<Vec<String>, io::Error> {
         let file = File::open(path)?;
         let reader = io::BufReader::new(file);
 
-        let lines: Vec<String> = reader.lines()
-            .filter_map(|line| line.ok())
+        // Lines that aren't valid UTF-8 are skipped, a read error ends the file
+        let lines: Vec<String> = reader.split(b'\n')
+            .map_while(Result::ok)
+            .filter_map(|line| String::from_utf8(line).ok())
             .map(|line| line.trim().to_string())
             .filter(|line| !line.is_empty() && !line.starts_with('#'))
             .collect();
@@ -294,12 +944,73 @@ fn get_order(file_path: &str) -> Result<Vec<String>, io::Error> {
     }
 }
 
+fn get_metadata_columns(file_path: &str) -> Result<Vec<String>, io::Error> {
+    if Path::new(file_path).exists() {
+        get_order(file_path)
+    } else {
+        Ok(DEFAULT_METADATA_COLUMNS.iter().map(|&s| s.to_string()).collect())
+    }
+}
+
+fn parse_path_map(line: &str) -> Option<(String, String)> {
+    let (from, to) = line.split_once('=')?;
+    let (from, to) = (from.trim(), to.trim());
+    if from.is_empty() || to.is_empty() {
+        return None;
+    }
+    Some((from.to_string(), to.to_string()))
+}
+
+// Mappings from the file come first so --map-path can add to them
+fn get_path_maps(file_path: &str) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    if !Path::new(file_path).exists() {
+        return Ok(Vec::new());
+    }
+    get_order(file_path)?
+        .iter()
+        .map(|line| parse_path_map(line).ok_or_else(|| format!("Invalid path mapping '{}' in {}, expected from=to", line, file_path).into()))
+        .collect()
+}
+
+// Swaps the longest matching prefix.  A prefix only matches whole folder names, /Volumes/SFX doesn't match /Volumes/SFX2
+fn replace_path_prefix<'a>(path: &str, maps: impl Iterator<Item = (&'a str, &'a str)>) -> String {
+    let matches = |prefix: &str| {
+        path.strip_prefix(prefix).is_some_and(|rest| rest.is_empty() || prefix.ends_with('/') || rest.starts_with('/'))
+    };
+    match maps.filter(|(from, _)| matches(from)).max_by_key(|(from, _)| from.len()) {
+        Some((from, to)) => format!("{}{}", to, &path[from.len()..]),
+        None => path.to_string(),
+    }
+}
+
+// A path as stored in the database, translated to where the file is on this machine
+fn map_path(path: &str, path_maps: &[(String, String)]) -> String {
+    replace_path_prefix(path, path_maps.iter().map(|(from, to)| (from.as_str(), to.as_str())))
+}
+
+// A path on this machine, translated back to how the database stores it
+fn unmap_path(path: &str, path_maps: &[(String, String)]) -> String {
+    replace_path_prefix(path, path_maps.iter().map(|(from, to)| (to.as_str(), from.as_str())))
+}
+
+fn get_protect_rules(file_path: &str) -> Result<Vec<ProtectRule>, Box<dyn Error>> {
+    if !Path::new(file_path).exists() {
+        return Ok(Vec::new());
+    }
+    get_order(file_path)?
+        .iter()
+        .map(|line| {
+            ProtectRule::parse(line).ok_or_else(|| format!("Invalid protect rule '{}' in {}, expected path:, library:, show: or sql:", line, file_path).into())
+        })
+        .collect()
+}
+
 fn get_tags(file_path: &str) -> Result<Vec<String>, rusqlite::Error> {
     println!("Gathering tags to search for");
     let path = Path::new(file_path);
     
     if path.exists() {
-        let file = File::open(&path).map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e)))?;
+        let file = File::open(path).map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e)))?;
         let reader = io::BufReader::new(file);
         let tags: Vec<String> = reader.lines()
             .filter_map(|line| {
@@ -320,6 +1031,66 @@ fn get_tags(file_path: &str) -> Result<Vec<String>, rusqlite::Error> {
     }
 }
 
+// Builds the path for a generated database from the naming template.
+// {name} is the source filename without extension, {ext} its extension (.sqlite, .db, ...), {suffix} the kind of output,
+// and {date}, {time} and {timestamp} the UTC time the run started.
+// An explicit output path is used as is, unless it's a folder, in which case the templated name goes inside it.
+fn get_output_path(source_db_path: &str, suffix: &str, output: &Option<String>, template: &str, started: &(String, String)) -> String {
+    if let Some(output) = output {
+        if !Path::new(output).is_dir() {
+            return output.clone();
+        }
+    }
+
+    let source = Path::new(source_db_path);
+    let name = source.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    let ext = source.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+    let (date, time) = started;
+    let filename = template
+        .replace("{name}", &name)
+        .replace("{suffix}", suffix)
+        .replace("{ext}", &ext)
+        .replace("{timestamp}", &format!("{}_{}", date, time))
+        .replace("{date}", date)
+        .replace("{time}", time);
+
+    let folder = match output {
+        Some(output) => Path::new(output).to_path_buf(),
+        None => source.parent().map(Path::to_path_buf).unwrap_or_default(),
+    };
+    folder.join(filename).display().to_string()
+}
+
+// Returns the current UTC date and time as (YYYY-MM-DD, HHMMSS)
+fn get_timestamp() -> (String, String) {
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let days = (seconds / 86400) as i64;
+    let remainder = seconds % 86400;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (
+        format!("{:04}-{:02}-{:02}", year, month, day),
+        format!("{:02}{:02}{:02}", remainder / 3600, (remainder % 3600) / 60, remainder % 60),
+    )
+}
+
+fn check_overwrite(path: &str, force: bool) -> Result<(), Box<dyn Error>> {
+    if !force && Path::new(path).exists() {
+        return Err(format!("{} already exists.  Use --force to overwrite it or choose another --output", path).into());
+    }
+    Ok(())
+}
+
 fn get_connection_source_filepath(conn: &Connection) -> String {
     let path = conn.path().unwrap(); // This gives you a &Path
     let path_str = path.to_str().unwrap().to_string().replace("_thinned", ""); // Converts &Path to String
@@ -338,8 +1109,10 @@ fn get_db_size(conn: &Connection,) -> usize {
 }
 
 fn get_root_filename(filename: &str) -> Option<String> {
-    // Use regex to strip off trailing pattern like .1, .M, but preserve file extension
-    let re = Regex::new(r"^(?P<base>.+?)(\.\d+|\.\w+)+(?P<ext>\.\w+)$").unwrap();
+    // Use regex to strip off trailing pattern like .1, .M, but preserve file extension.
+    // It's compiled once since deep dive runs it for every record in the database.
+    static ROOT_FILENAME: OnceLock<Regex> = OnceLock::new();
+    let re = ROOT_FILENAME.get_or_init(|| Regex::new(r"^(?P<base>.+?)(\.\d+|\.\w+)+(?P<ext>\.\w+)$").unwrap());
     if let Some(caps) = re.captures(filename) {
         Some(format!("{}{}", &caps["base"], &caps["ext"]))
     } else {
@@ -348,85 +1121,139 @@ fn get_root_filename(filename: &str) -> Option<String> {
     }
 }
 
+// Reads any column type as text so numeric and text columns can be used in match keys
+fn get_value_string(row: &rusqlite::Row, index: usize) -> String {
+    row.get_ref(index).map(value_ref_string).unwrap_or_default()
+}
 
+fn value_ref_string(value: ValueRef) -> String {
+    match value {
+        ValueRef::Text(text) => String::from_utf8_lossy(text).to_string(),
+        ValueRef::Integer(value) => value.to_string(),
+        ValueRef::Real(value) => value.to_string(),
+        _ => String::new(),
+    }
+}
 
-// DUPLICATES DB
-fn create_duplicates_db(source_db_path: &str, dupe_records_to_keep: &HashSet<FileRecord>) -> Result<()> {
-    println!("Generating Duplicates Only Database.  This can take awhile.");
-    let duplicate_db_path = format!("{}_dupes.sqlite", &source_db_path.trim_end_matches(".sqlite"));
-    fs::copy(&source_db_path, &duplicate_db_path).unwrap();
-    let mut dupe_conn = Connection::open(&duplicate_db_path)?;
-    
-    let mut dupe_records_to_delete = fetch_filerecords_from_database(&dupe_conn)?;
-    dupe_records_to_delete.retain(|record| !dupe_records_to_keep.contains(record));
-    
-    delete_file_records(&mut dupe_conn, &dupe_records_to_delete, false)?;
-    vacuum_db(&dupe_conn)?;
+// Soundminer stores durations as text like 00:01:02.500 (HH:MM:SS.mmm), shorter forms and plain seconds are accepted too
+fn parse_duration(text: &str) -> Option<Duration> {
+    let mut seconds = 0.0;
+    for part in text.trim().split(':') {
+        seconds = seconds * 60.0 + part.trim().parse::<f64>().ok()?;
+    }
+    if seconds.is_finite() && seconds >= 0.0 {
+        Some(Duration::from_secs_f64(seconds))
+    } else {
+        None
+    }
+}
 
-    println!("{} records moved to {}", get_db_size(&dupe_conn), duplicate_db_path);
+// Case, extension, separators and deep dive suffixes are ignored: Door_Slam.1.WAV -> door slam
+fn get_normalized_filename(filename: &str) -> String {
+    let root = get_root_filename(filename).unwrap_or_else(|| filename.to_string());
+    let stem = match root.rsplit_once('.') {
+        Some((stem, _)) => stem.to_string(),
+        None => root,
+    };
+    stem.to_lowercase()
+        .split(|c: char| c.is_whitespace() || c == '_' || c == '-')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
 
-    Ok(())
+fn get_file_hash(filepath: &str) -> Option<String> {
+    let mut file = File::open(filepath).ok()?;
+    let mut hasher = Sha256::new();
+    let length = io::copy(&mut file, &mut hasher).ok()?;
+    let digest: String = hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect();
+    Some(format!("{}:{}", digest, length))
 }
 
+// Splits a multi-mono channel file into its set name and channel, Amb.1.L.wav -> (Amb.1, L)
+fn get_channel_set(filename: &str) -> Option<(String, String)> {
+    let (stem, _ext) = filename.rsplit_once('.')?;
+    let (set_name, channel) = stem.rsplit_once('.')?;
+    if set_name.is_empty() || !CHANNEL_SUFFIXES.contains(&channel) {
+        return None;
+    }
+    Some((set_name.to_string(), channel.to_string()))
+}
 
-//FETCH FUNCTIONS
-fn fetch_filerecords_from_database(conn: &Connection) -> Result<HashSet<FileRecord>> {
-    println!("Gathering records from {}", get_connection_source_filepath(&conn));
-    let mut stmt = conn.prepare("SELECT rowid, filename, duration FROM justinmetadata")?;
-    let file_records: HashSet<FileRecord> = stmt.query_map([], |row| {
-        Ok(FileRecord {
-            id: row.get(0)?,
-            filename: row.get(1)?,
-            duration: row.get(2)?,
-        })
+fn get_channel_set_key(pathname: Option<String>, set_name: &str) -> String {
+    Path::new(&pathname.unwrap_or_default()).join(set_name).display().to_string()
+}
+
+// SQL versions of the deep dive helpers so grouping can happen inside a single query:
+//   smdupe_root_name(filename) -> Door.1.M.wav becomes Door.wav
+//   smdupe_channel_set(pathname, filename) -> folder and set name of a multi-mono channel file, otherwise NULL
+fn register_sql_functions(conn: &Connection) -> Result<()> {
+    let flags = FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC;
+    conn.create_scalar_function("smdupe_root_name", 1, flags, |ctx| {
+        let filename: Option<String> = ctx.get(0)?;
+        Ok(filename.map(|filename| get_root_filename(&filename).unwrap_or(filename)))
+    })?;
+    conn.create_scalar_function("smdupe_channel_set", 2, flags, |ctx| {
+        let pathname: Option<String> = ctx.get(0)?;
+        let filename: Option<String> = ctx.get(1)?;
+        Ok(filename
+            .and_then(|filename| get_channel_set(&filename))
+            .map(|(set_name, _)| get_channel_set_key(pathname, &set_name)))
+    })?;
+    Ok(())
+}
+
+// Columns of justinmetadata without the INTEGER PRIMARY KEY, which is just an alias for the rowid
+fn get_data_columns(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("PRAGMA table_info(justinmetadata)")?;
+    let columns = stmt.query_map([], |row| {
+        let name: String = row.get(1)?;
+        let column_type: String = row.get(2)?;
+        let primary_key: i64 = row.get(5)?;
+        Ok((name, primary_key > 0 && column_type.eq_ignore_ascii_case("INTEGER")))
     })?
-    .filter_map(Result::ok)
-    .collect();
+    .collect::<Result<Vec<(String, bool)>>>()?;
 
-    Ok(file_records)
+    Ok(columns.into_iter().filter(|(_, rowid_alias)| !rowid_alias).map(|(name, _)| name).collect())
 }
 
-fn extract_filenames_set_from_records(file_records: &HashSet<FileRecord>) -> HashSet<String> {
-    file_records.iter().map(|record| record.filename.clone()).collect()
+fn quote_columns(columns: &[String]) -> String {
+    columns.iter().map(|column| format!("\"{}\"", column)).collect::<Vec<_>>().join(", ")
 }
 
+// Follows keeper links until reaching a record that isn't being removed itself
+fn resolve_keeper(state: &WorkState, marked_ids: &RowIdSet, id: usize) -> Result<Option<usize>> {
+    let Some(mut keeper) = state.keeper(id)? else { return Ok(None) };
+    for _ in 0..marked_ids.len() {
+        if !marked_ids.contains(keeper) {
+            return Ok(Some(keeper));
+        }
+        match state.keeper(keeper)? {
+            Some(next) => keeper = next,
+            None => return Ok(None),
+        }
+    }
+    Ok(None)
+}
 
-// GATHER FUNCTIONS
-fn gather_compare_database_overlaps(target_conn: &Connection, compare_conn: &Connection) -> Result<HashSet<FileRecord>> {
-    
-    let compare_records = fetch_filerecords_from_database(&compare_conn)?;
-    let filenames_to_check = extract_filenames_set_from_records(&compare_records);
-    let mut matching_records = fetch_filerecords_from_database(&target_conn)?;
-    println!("Comparing filenames between {} and {}", target_conn.path().unwrap().display(), compare_conn.path().unwrap().display());
-    matching_records.retain(|record| filenames_to_check.contains(&record.filename));
+fn get_table_columns(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("PRAGMA table_info(justinmetadata)")?;
+    let columns = stmt.query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<String>>>()?;
+    Ok(columns)
+}
 
-    if matching_records.is_empty() {
-        println!("NO OVERLAPPING FILE RECORDS FOUND!");
-    } else {
-        println!(
-            "Found {} overlapping file records between {} and {}.",
-            matching_records.len(),
-            get_connection_source_filepath(&target_conn),
-            get_connection_source_filepath(&compare_conn)
-        );
-    }
-
-    Ok(matching_records)
-}
-
-fn gather_duplicate_filenames_in_database(conn: &mut Connection, group_sort: &Option<String>, group_null: bool, verbose: bool) -> Result<HashSet<FileRecord>, rusqlite::Error> {
-    println!("Searching {} for duplicate records", get_connection_source_filepath(&conn));
-    let mut file_records = HashSet::new();
+fn get_order_clause(verbose: bool) -> Result<String> {
     let order = get_order(ORDER_FILE_PATH).map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e)))?;
     if verbose {
         for line in &order {println!("{}", line);}
     }
+    Ok(order.join(", "))
+}
 
-    // Construct the ORDER BY clause dynamically
-    let order_clause = order.join(", ");
-
-    // Build the SQL query based on whether a group_sort is provided
-    let (partition_by, where_clause) = match group_sort {
+// Returns the PARTITION BY prefix and WHERE clause used to search for duplicates within a column grouping
+fn get_group_clauses(group_sort: &Option<String>, group_null: bool) -> (String, String) {
+    match group_sort {
         Some(group) => {
             println!("Grouping duplicate record search by {}", group);
             
@@ -438,11 +1265,286 @@ fn gather_duplicate_filenames_in_database(conn: &mut Connection, group_sort: &Op
                 format!("WHERE {} IS NOT NULL AND {} != ''", group, group)
             };
             
-            (format!("{}, filename", group), where_clause)
+            (format!("{}, ", group), where_clause)
+        }
+        None => (String::new(), String::new()),
+    }
+}
+
+
+
+// DUPLICATES DB
+// Builds a new database holding only the records being removed and the records kept in their place.
+// Each row is annotated with its duplicate group (the keeper's rowid), whether it was kept, and why it was removed.
+fn create_duplicates_db(source_db_path: &str, duplicate_db_path: &str, records: &RowIdSet, state: &WorkState, reasons: &Reasons, note_column: &Option<String>) -> Result<(), Box<dyn Error>> {
+    println!("Generating Duplicates Only Database.  This can take awhile.");
+    if Path::new(duplicate_db_path).exists() {
+        fs::remove_file(duplicate_db_path)?;
+    }
+    let mut dupe_conn = Connection::open(duplicate_db_path)?;
+    dupe_conn.execute("ATTACH DATABASE ? AS source", [source_db_path])?;
+    copy_database_schema(&dupe_conn)?;
+
+    let mut kept_counts: HashMap<usize, usize> = HashMap::new();
+    for id in records.iter() {
+        if let Some(keeper) = resolve_keeper(state, records, id)? {
+            *kept_counts.entry(keeper).or_insert(0) += 1;
+        }
+    }
+
+    let columns = quote_columns(&get_data_columns(&dupe_conn)?);
+    dupe_conn.execute("ALTER TABLE main.justinmetadata ADD COLUMN SMDupe_Group INTEGER", [])?;
+    dupe_conn.execute("ALTER TABLE main.justinmetadata ADD COLUMN SMDupe_Role TEXT", [])?;
+    dupe_conn.execute("ALTER TABLE main.justinmetadata ADD COLUMN SMDupe_Reason TEXT", [])?;
+
+    let note_column = match note_column {
+        Some(column) => match get_table_columns(&dupe_conn)?.into_iter().find(|c| c.eq_ignore_ascii_case(column)) {
+            Some(found) => Some(found),
+            None => {
+                println!("Column {} not found, duplicate notes will only be in the SMDupe columns", column);
+                None
+            }
+        },
+        None => None,
+    };
+
+    let tx = dupe_conn.transaction()?;
+    {
+        let mut insert = tx.prepare(&format!(
+            "INSERT INTO main.justinmetadata (rowid, {}, SMDupe_Group, SMDupe_Role, SMDupe_Reason) SELECT rowid, {}, ?, ?, ? FROM source.justinmetadata WHERE rowid = ?",
+            columns, columns
+        ))?;
+        for id in records.iter() {
+            let group = resolve_keeper(state, records, id)?;
+            let reason = reasons.get(id).unwrap_or("marked for deletion");
+            insert.execute(rusqlite::params![group, "REMOVE", reason, id])?;
+        }
+        for (keeper, count) in &kept_counts {
+            insert.execute(rusqlite::params![keeper, "KEEP", format!("kept over {} duplicates", count), keeper])?;
+        }
+
+        if let Some(column) = &note_column {
+            tx.execute(&format!(
+                "UPDATE main.justinmetadata SET \"{}\" = TRIM(COALESCE(\"{}\", '') || ' ' || 'SMDupe ' || SMDupe_Role || COALESCE(' group ' || SMDupe_Group, '') || ': ' || SMDupe_Reason)",
+                column, column
+            ), [])?;
+        }
+    }
+    tx.commit()?;
+    dupe_conn.execute("DETACH DATABASE source", [])?;
+
+    println!("{} records to remove and {} records kept in their place written to {}", records.len(), kept_counts.len(), duplicate_db_path);
+
+    Ok(())
+}
+
+// Recreates the source tables, indexes, views and triggers in main and copies every table except justinmetadata.
+// Virtual tables are left empty and their shadow tables are created by SQLite itself.
+fn copy_database_schema(conn: &Connection) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "SELECT type, name, sql FROM source.sqlite_master
+         WHERE sql IS NOT NULL AND name NOT LIKE 'sqlite_%'
+         ORDER BY CASE type WHEN 'table' THEN 0 WHEN 'index' THEN 1 WHEN 'view' THEN 2 ELSE 3 END"
+    )?;
+    let schema = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?)))?
+        .collect::<Result<Vec<(String, String, String)>>>()?;
+
+    let virtual_tables: Vec<&String> = schema.iter()
+        .filter(|(_, _, sql)| sql.to_uppercase().starts_with("CREATE VIRTUAL TABLE"))
+        .map(|(_, name, _)| name)
+        .collect();
+    let is_shadow_table = |name: &str| virtual_tables.iter().any(|table| name.starts_with(&format!("{}_", table)));
+
+    for (object_type, name, sql) in &schema {
+        if object_type == "table" && is_shadow_table(name) {
+            continue;
+        }
+        conn.execute_batch(sql)?;
+        let is_virtual = virtual_tables.contains(&name);
+        if object_type == "table" && !is_virtual && name != "justinmetadata" {
+            conn.execute(&format!("INSERT INTO main.\"{}\" SELECT * FROM source.\"{}\"", name, name), [])?;
+        }
+    }
+    Ok(())
+}
+
+
+//FETCH FUNCTIONS
+// Calls f with each record matching the filter (e.g. a WHERE clause) one row at a time, so the table is never held in memory.
+// Columns missing from the database are read as NULL.  Paths are as stored, map_path() finds the files on this machine.
+fn for_each_file_record<F>(conn: &Connection, filter: &str, mut f: F) -> Result<()>
+where
+    F: FnMut(FileRecord) -> Result<()>,
+{
+    let table_columns = get_table_columns(conn)?;
+    let find_column = |column: &str| table_columns.iter().find(|c| c.eq_ignore_ascii_case(column)).map(|found| format!("\"{}\"", found));
+    let mut columns: Vec<String> = FILE_RECORD_COLUMNS.iter().map(|column| find_column(column).unwrap_or_else(|| "NULL".to_string())).collect();
+    columns.push(SIZE_COLUMNS.iter().find_map(|column| find_column(column)).unwrap_or_else(|| "NULL".to_string()));
+    let columns = columns.join(", ");
+
+    let mut stmt = conn.prepare(&format!("SELECT rowid, {} FROM justinmetadata {}", columns, filter))?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let text = |index: usize| Some(get_value_string(row, index)).filter(|value| !value.is_empty());
+        let number = |index: usize| text(index).and_then(|value| value.trim().parse::<f64>().ok()).map(|value| value as u32);
+        f(FileRecord {
+            id: row.get(0)?,
+            filename: get_value_string(row, 1),
+            pathname: text(2),
+            duration: text(3).and_then(|value| parse_duration(&value)),
+            sample_rate: number(4),
+            bit_depth: number(5),
+            channels: number(6),
+            library: text(7),
+            show: text(8),
+            filepath: text(9),
+            size: text(10).and_then(|value| value.trim().parse::<f64>().ok()).map(|value| value as u64),
+        })?;
+    }
+    Ok(())
+}
+
+// Builds the match key for every record.  With a group column the group value is part of the key,
+// NULL and empty group entries are treated alike and only included when group_null is set.
+// Records without a usable key (e.g. an unreadable file when hashing) are left out.
+fn fetch_compare_keys(conn: &Connection, compare_key: CompareKey, group_sort: &Option<String>, group_null: bool, path_maps: &[(String, String)]) -> Result<Vec<(usize, String)>> {
+    let mut keys = Vec::new();
+    for_each_compare_key(conn, compare_key, group_sort, group_null, path_maps, |id, key| keys.push((id, key)))?;
+    Ok(keys)
+}
+
+fn for_each_compare_key<F>(conn: &Connection, compare_key: CompareKey, group_sort: &Option<String>, group_null: bool, path_maps: &[(String, String)], mut f: F) -> Result<()>
+where
+    F: FnMut(usize, String),
+{
+    let (key_columns, where_clause) = get_compare_key_columns(compare_key, group_sort, group_null);
+    let query = format!("SELECT rowid, {} FROM justinmetadata {}", key_columns, where_clause);
+
+    if compare_key == CompareKey::Hash {
+        println!("Hashing files referenced by {}.  This can take awhile.", get_connection_source_filepath(conn));
+    }
+
+    let mut stmt = conn.prepare(&query)?;
+    let column_count = stmt.column_count();
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let values: Vec<String> = (1..column_count).map(|index| get_value_string(row, index)).collect();
+        if let Some(key) = get_compare_key(compare_key, &values, path_maps) {
+            f(row.get(0)?, key);
         }
-        None => ("filename".to_string(), String::new()),
+    }
+    Ok(())
+}
+
+// The group column followed by the columns a compare key is built from, and the filter for the group column
+fn get_compare_key_columns(compare_key: CompareKey, group_sort: &Option<String>, group_null: bool) -> (String, String) {
+    let (group_column, where_clause) = match group_sort {
+        Some(group) if group_null => (format!("COALESCE({}, '')", group), String::new()),
+        Some(group) => (format!("COALESCE({}, '')", group), format!("WHERE {} IS NOT NULL AND {} != ''", group, group)),
+        None => ("''".to_string(), String::new()),
+    };
+    let value_columns = match compare_key {
+        CompareKey::Filename | CompareKey::Normalized => "filename",
+        CompareKey::Duration => "filename, duration",
+        CompareKey::Format => "filename, sampleRate, channels",
+        CompareKey::Hash | CompareKey::Path => "filepath",
+    };
+    (format!("{}, {}", group_column, value_columns), where_clause)
+}
+
+// The match key from the values of get_compare_key_columns(), group value first
+fn get_compare_key(compare_key: CompareKey, values: &[String], path_maps: &[(String, String)]) -> Option<String> {
+    let key = match compare_key {
+        CompareKey::Filename => values[1].clone(),
+        CompareKey::Duration => format!("{}\t{}", values[1], values[2]),
+        CompareKey::Format => format!("{}\t{}\t{}", values[1], values[2], values[3]),
+        CompareKey::Normalized => get_normalized_filename(&values[1]),
+        CompareKey::Hash => get_file_hash(&map_path(&values[1], path_maps))?,
+        CompareKey::Path => map_path(&values[1], path_maps),
     };
+    Some(format!("{}\t{}", values[0], key))
+}
+
+fn fetch_rowids(conn: &Connection, query: &str) -> Result<RowIdSet> {
+    let mut stmt = conn.prepare(query)?;
+    let mut rows = stmt.query([])?;
+    let mut ids = RowIdSet::new();
+    while let Some(row) = rows.next()? {
+        ids.insert(row.get(0)?);
+    }
+    Ok(ids)
+}
+
+// GATHER FUNCTIONS
+fn gather_compare_database_overlaps(target_conn: &Connection, compare_conn: &Connection, compare_key: CompareKey, group_sort: &Option<String>, group_null: bool, path_maps: &[(String, String)]) -> Result<RowIdSet> {
     
+    println!("Comparing {} between {} and {}", compare_key.describe(), target_conn.path().unwrap().display(), compare_conn.path().unwrap().display());
+    if let Some(group) = group_sort {
+        println!("Only matching records within the same {}", group);
+    }
+
+    let mut matching_records = RowIdSet::new();
+    if compare_key == CompareKey::Hash {
+        // File hashes are computed here, so the reference hashes are kept in memory
+        let mut compare_keys: HashSet<String> = HashSet::new();
+        for_each_compare_key(compare_conn, compare_key, group_sort, group_null, path_maps, |_, key| {
+            compare_keys.insert(key);
+        })?;
+        for_each_compare_key(target_conn, compare_key, group_sort, group_null, path_maps, |id, key| {
+            if compare_keys.contains(&key) {
+                matching_records.insert(id);
+            }
+        })?;
+    } else {
+        // The reference database is attached and matched in SQL.  SQLite keeps the reference keys of the IN
+        // in a temporary index, which spills to disk, so a large reference library doesn't have to fit in memory.
+        let compare_path = compare_conn.path().map(|path| path.to_string_lossy().to_string()).unwrap_or_default();
+        let owned_maps = path_maps.to_vec();
+        target_conn.create_scalar_function("smdupe_compare_key", -1, FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC, move |ctx| {
+            let values: Vec<String> = (0..ctx.len()).map(|index| value_ref_string(ctx.get_raw(index))).collect();
+            Ok(get_compare_key(compare_key, &values, &owned_maps))
+        })?;
+        target_conn.execute("ATTACH DATABASE ? AS smdupe_compare", [&compare_path])?;
+
+        let (key_columns, where_clause) = get_compare_key_columns(compare_key, group_sort, group_null);
+        let filter = if where_clause.is_empty() { "WHERE" } else { "AND" };
+        let query = format!(
+            "SELECT rowid FROM main.justinmetadata {} {} smdupe_compare_key({}) IN (SELECT smdupe_compare_key({}) FROM smdupe_compare.justinmetadata {})",
+            where_clause, filter, key_columns, key_columns, where_clause
+        );
+        // The reference is detached again even when the query fails, so the next reference can be attached
+        let matched = fetch_rowids(target_conn, &query);
+        target_conn.execute("DETACH DATABASE smdupe_compare", [])?;
+        matching_records = matched?;
+    }
+
+    if matching_records.is_empty() {
+        println!("NO OVERLAPPING FILE RECORDS FOUND!");
+    } else {
+        println!(
+            "Found {} overlapping file records between {} and {}.",
+            matching_records.len(),
+            get_connection_source_filepath(target_conn),
+            get_connection_source_filepath(compare_conn)
+        );
+    }
+
+    Ok(matching_records)
+}
+
+fn gather_duplicate_filenames_in_database(conn: &mut Connection, group_sort: &Option<String>, group_null: bool, duration_tolerance: Option<DurationTolerance>, keepers: &mut HashMap<usize, usize>, verbose: bool) -> Result<RowIdSet, rusqlite::Error> {
+    println!("Searching {} for duplicate records", get_connection_source_filepath(conn));
+    let mut file_records = RowIdSet::new();
+    let order_clause = get_order_clause(verbose)?;
+    let (mut partition_prefix, where_clause) = get_group_clauses(group_sort, group_null);
+
+    // With a tolerance, same-name records are only duplicates when their durations are close enough
+    let mut removable = None;
+    if let Some(tolerance) = duration_tolerance {
+        removable = Some(register_duration_clusters(conn, group_sort, &where_clause, "filename", tolerance, verbose)?);
+        partition_prefix = format!("{}smdupe_duration_cluster(rowid), ", partition_prefix);
+    }
+
     let sql = format!(
         "
         WITH ranked AS (
@@ -450,21 +1552,23 @@ fn gather_duplicate_filenames_in_database(conn: &mut Connection, group_sort: &Op
                 rowid AS id,
                 filename,
                 duration,
-                ROW_NUMBER() OVER (
-                    PARTITION BY {}
-                    ORDER BY {}
-                ) as rn
+                ROW_NUMBER() OVER ranking as rn,
+                FIRST_VALUE(rowid) OVER ranking as keeper_id
             FROM justinmetadata
             {}
+            WINDOW ranking AS (
+                PARTITION BY {}filename
+                ORDER BY {}
+            )
         )
-        SELECT id, filename, duration FROM ranked WHERE rn > 1
+        SELECT id, keeper_id FROM ranked WHERE rn > 1
         ",
-        partition_by, order_clause, where_clause
+        where_clause, partition_prefix, order_clause
     );
     
     let total_overlaps = count_total_duplicate_filenames(conn).unwrap();
     let unique_filenames = count_unique_duplicate_filenames(conn).unwrap();
-    let total = total_overlaps - unique_filenames;
+    let total = removable.unwrap_or(total_overlaps - unique_filenames);
     
     if verbose {
         println!("SQL found {} duplicate records with {} unique filenames", total_overlaps, unique_filenames);
@@ -473,226 +1577,1014 @@ fn gather_duplicate_filenames_in_database(conn: &mut Connection, group_sort: &Op
     println!("Processing which filenames are best for removal");
 
     let mut stmt = conn.prepare(&sql)?;
-    let rows = stmt.query_map([], |row| {
-        Ok(FileRecord {
-            id: row.get(0)?,
-            filename: row.get(1)?,
-            duration: row.get(2)?,
-        })
-    })?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, usize>(0)?, row.get::<_, usize>(1)?)))?;
 
-    let mut counter: usize = 1;
-
-    for file_record in rows {
-        file_records.insert(file_record?);
+    for (counter, row) in rows.enumerate() {
+        let (id, keeper_id) = row?;
+        keepers.insert(id, keeper_id);
+        file_records.insert(id);
         let _ = io::stdout().flush();
-        print!("\r{} / {}", counter, total);
-        counter += 1;
+        print!("\r{} / {}", counter + 1, total);
     }
     println!("\nMarked {} duplicate records for deletion.", file_records.len());
 
     Ok(file_records)
 }
 
-fn gather_records_with_trailing_numbers(conn: &mut Connection, total: usize) -> Result<HashSet<FileRecord>, rusqlite::Error> {
+// Registers smdupe_duration_cluster(rowid), the duration cluster of every record sharing a name (the name column or
+// expression, e.g. the deep dive root name) with others, for use in a PARTITION BY.  Returns how many records the
+// clusters leave to remove.
+fn register_duration_clusters(conn: &Connection, group_sort: &Option<String>, where_clause: &str, name: &str, tolerance: DurationTolerance, verbose: bool) -> Result<usize> {
+    let (clusters, removable) = get_duration_clusters(conn, group_sort, where_clause, name, tolerance, verbose)?;
+    conn.create_scalar_function("smdupe_duration_cluster", 1, FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC, move |ctx| {
+        let id: usize = ctx.get(0)?;
+        Ok(clusters.get(&id).copied().unwrap_or(0))
+    })?;
+    Ok(removable)
+}
+
+// Splits each set of same-name records into clusters of similar duration, returning the cluster of every record and
+// how many records are left to remove once every cluster keeps one.
+// Durations are taken shortest first and a new cluster starts whenever one is too far from the start of the current cluster.
+// Records without a readable duration form a cluster of their own.  Names that end up in several clusters are reported.
+fn get_duration_clusters(conn: &Connection, group_sort: &Option<String>, where_clause: &str, name: &str, tolerance: DurationTolerance, verbose: bool) -> Result<(HashMap<usize, usize>, usize)> {
+    let group_column = match group_sort {
+        Some(group) => format!("COALESCE({}, '')", group),
+        None => "''".to_string(),
+    };
+    let filter = if where_clause.is_empty() { "WHERE" } else { "AND" };
+    let query = format!(
+        "SELECT rowid, {}, {}, duration FROM justinmetadata {} {} {} IN (SELECT {} FROM justinmetadata GROUP BY 1 HAVING COUNT(*) > 1)",
+        group_column, name, where_clause, filter, name, name
+    );
+
+    // Durations and rowids of the records sharing a group value and filename
+    type SameName = Vec<(Option<Duration>, usize)>;
+    let mut names: HashMap<(String, String), SameName> = HashMap::new();
+    let mut stmt = conn.prepare(&query)?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let duration = parse_duration(&get_value_string(row, 3));
+        names.entry((get_value_string(row, 1), get_value_string(row, 2))).or_default().push((duration, row.get(0)?));
+    }
+
+    let mut clusters = HashMap::new();
+    let mut removable = 0;
+    let mut conflicts: Vec<(String, Vec<Duration>)> = Vec::new();
+    for ((_, filename), mut records) in names {
+        records.sort();
+        let undated = usize::from(records[0].0.is_none());
+        removable += records.len();
+        let mut cluster = 0;
+        let mut anchor: Option<Duration> = None;
+        let mut starts = Vec::new();
+        for (duration, id) in records {
+            if let Some(duration) = duration {
+                if anchor.is_none_or(|anchor| !tolerance.allows(anchor, duration)) {
+                    cluster += 1;
+                    anchor = Some(duration);
+                    starts.push(duration);
+                }
+            }
+            clusters.insert(id, cluster);
+        }
+        removable -= starts.len() + undated;
+        if starts.len() > 1 {
+            conflicts.push((filename, starts));
+        }
+    }
+
+    if !conflicts.is_empty() {
+        conflicts.sort();
+        println!("{} filenames are shared by different sounds and were kept apart (same name, different sound):", conflicts.len());
+        let shown = if verbose { conflicts.len() } else { 10 };
+        for (filename, starts) in conflicts.iter().take(shown) {
+            let durations: Vec<String> = starts.iter().map(|duration| format!("{:.3}s", duration.as_secs_f64())).collect();
+            println!("    {}: durations from {}", filename, durations.join(", "));
+        }
+        if conflicts.len() > shown {
+            println!("    ... and {} more (use -v to list them all)", conflicts.len() - shown);
+        }
+    }
+
+    Ok((clusters, removable))
+}
+
+fn gather_records_with_trailing_numbers(conn: &Connection, group_sort: &Option<String>, group_null: bool, duration_tolerance: Option<DurationTolerance>, keepers: &mut HashMap<usize, usize>, verbose: bool) -> Result<RowIdSet, rusqlite::Error> {
     println!("Performing Deep Dive Search for Similar Records ending with .1 (or multiple numbers) or .M");
 
-    let mut file_records = HashSet::new();
-    let mut file_groups: HashMap<String, Vec<FileRecord>> = HashMap::new();
+    let mut file_records = RowIdSet::new();
+    let order_clause = get_order_clause(verbose)?;
+    let (mut partition_prefix, where_clause) = get_group_clauses(group_sort, group_null);
+    register_sql_functions(conn)?;
 
-    let mut stmt = conn.prepare("SELECT rowid, filename, duration FROM justinmetadata")?;
-    let rows = stmt.query_map([], |row| {
-        Ok(FileRecord {
-            id: row.get(0)?,
-            filename: row.get(1)?,
-            duration: row.get(2)?,
+    // The duration tolerance keeps different sounds sharing a root name apart, like in the filename search
+    if let Some(tolerance) = duration_tolerance {
+        register_duration_clusters(conn, group_sort, &where_clause, "smdupe_root_name(filename)", tolerance, verbose)?;
+        partition_prefix = format!("{}smdupe_duration_cluster(smdupe_id), ", partition_prefix);
+    }
+
+    // Multi-mono channel files in the same folder share an asset key so the set is kept or removed as a whole.
+    // A channel set is ranked against interleaved files using the combined channel count of its members.
+    let columns = get_table_columns(conn)?
+        .iter()
+        .map(|column| {
+            if column.eq_ignore_ascii_case("channels") {
+                format!(
+                    "CASE WHEN channel_set IS NULL THEN \"{}\" ELSE SUM(COALESCE(\"{}\", 1)) OVER (PARTITION BY channel_set) END AS \"{}\"",
+                    column, column, column
+                )
+            } else {
+                format!("\"{}\"", column)
+            }
         })
-    })?;
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    // The exact root filename and then the lowest rowid break any ties left by the order rules.
+    // Every record outside of the best ranked asset is removed, so channel sets are never split.
+    let sql = format!(
+        "
+        WITH roots AS (
+            SELECT *, rowid AS smdupe_id, smdupe_root_name(filename) AS root_name, smdupe_channel_set(pathname, filename) AS channel_set
+            FROM justinmetadata
+        ),
+        assets AS (
+            SELECT {}, smdupe_id, root_name, COALESCE(channel_set, '#' || smdupe_id) AS asset_key
+            FROM roots
+        ),
+        ranked AS (
+            SELECT
+                smdupe_id AS id,
+                filename,
+                duration,
+                asset_key,
+                FIRST_VALUE(asset_key) OVER ranking as keeper_asset,
+                FIRST_VALUE(smdupe_id) OVER ranking as keeper_id
+            FROM assets
+            {}
+            WINDOW ranking AS (
+                PARTITION BY {}root_name
+                ORDER BY {}, CASE WHEN filename = root_name THEN 0 ELSE 1 END ASC, smdupe_id ASC
+            )
+        )
+        SELECT id, keeper_id FROM ranked WHERE asset_key != keeper_asset
+        ",
+        columns, where_clause, partition_prefix, order_clause
+    );
 
     println!("Analyzing Records");
-    let mut counter: usize = 1;
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, usize>(0)?, row.get::<_, usize>(1)?)))?;
 
     for row in rows {
-        let file_record = row?;
-        
-        let base_filename = get_root_filename(&file_record.filename)
-            .unwrap_or_else(|| file_record.filename.clone());
+        let (id, keeper_id) = row?;
+        keepers.insert(id, keeper_id);
+        file_records.insert(id);
+    }
 
-        let _ = io::stdout().flush();
-        print!("\r{} / {}", counter, total);
-        counter += 1;
+    println!("Found {} total records ending in .1 or .M", file_records.len());
 
-        file_groups
-            .entry(base_filename)
-            .or_insert_with(Vec::new)
-            .push(file_record);
+    Ok(file_records)
+}
+
+// Unmarks multi-mono channel files whose set would otherwise only be partially removed.
+// A set is partially removed when some of its channels would lose every copy while others keep one.
+fn spare_partial_channel_sets(conn: &Connection, records: &mut RowIdSet, verbose: bool) -> Result<()> {
+    let mut channel_sets: HashMap<String, HashMap<String, Vec<usize>>> = HashMap::new();
+
+    for_each_file_record(conn, "", |record| {
+        if let Some((set_name, channel)) = get_channel_set(&record.filename) {
+            channel_sets
+                .entry(get_channel_set_key(record.pathname, &set_name))
+                .or_default()
+                .entry(channel)
+                .or_default()
+                .push(record.id);
+        }
+        Ok(())
+    })?;
+
+    let mut spared_ids = Vec::new();
+    for (set, channels) in channel_sets {
+        let removed = channels.values()
+            .filter(|ids| ids.iter().all(|id| records.contains(*id)))
+            .count();
+        if removed > 0 && removed < channels.len() {
+            if verbose {
+                println!("Keeping multichannel set {} whole ({} of {} channels were marked)", set, removed, channels.len());
+            }
+            spared_ids.extend(channels.into_values().flatten());
+        }
     }
 
-    for (root, records) in file_groups {
- 
-        if records.len() <= 1 {
-            continue;
+    let spared = spared_ids.into_iter().filter(|id| records.remove(*id)).count();
+    if spared > 0 {
+        println!("Spared {} records to keep multichannel sets whole", spared);
+    }
+
+    Ok(())
+}
+
+
+fn gather_filenames_with_tags(conn: &mut Connection, group_sort: &Option<String>, group_null: bool, verbose: bool) -> Result<RowIdSet> {
+    println!("Searching {} for filenames containing tags", get_connection_source_filepath(conn));
+    let mut file_records = RowIdSet::new();
+    let (_, where_clause) = get_group_clauses(group_sort, group_null);
+
+    let tags = get_tags(TAG_FILE_PATH)?;
+    if tags.is_empty() {
+        println!("No tags to search for");
+        return Ok(file_records);
+    }
+
+    // All tags are matched in one pass over the table, ignoring ASCII case.  Unlike the SQL LIKE this replaced, every
+    // character is literal: `_` and `%` in a tag only match those characters (-GAIN_ does not match Hit-GAIN.wav).
+    let matcher = AhoCorasick::builder()
+        .ascii_case_insensitive(true)
+        .build(&tags)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e)))?;
+    let mut tag_counts = vec![0usize; tags.len()];
+
+    for_each_file_record(conn, &where_clause, |record| {
+        // Every tag found in the filename is counted, even when several tags match the same record
+        let mut found: Vec<usize> = matcher.find_overlapping_iter(&record.filename).map(|m| m.pattern().as_usize()).collect();
+        if found.is_empty() {
+            return Ok(());
         }
-        
-        let root_found = records.iter().any(|record| record.filename == root);
-        
-        if root_found {
-            file_records.extend(
-                records.into_iter().filter(|record| record.filename != root)
-            );
-        } else {
-            file_records.extend(
-                records.into_iter().skip(1)
-            );
+        found.sort_unstable();
+        found.dedup();
+        for tag in found {
+            tag_counts[tag] += 1;
+        }
+        file_records.insert(record.id);
+        Ok(())
+    })?;
+
+    if verbose {
+        for (tag, count) in tags.iter().zip(&tag_counts) {
+            if *count > 0 {
+                println!("Filenames found for tag '{}': {}", tag, count);
+            }
         }
     }
+    println!("{} total records containing tags marked for deletion", file_records.len());
+    Ok(file_records)
+}
+
 
-    println!("\nFound {} total records ending in .1 or .M", file_records.len());
+// ORPHANS
+// Marks records whose file isn't on disk anymore.  Records without a file path are left alone.
+fn gather_orphaned_records(conn: &Connection, path_maps: &[(String, String)], verbose: bool) -> Result<RowIdSet> {
+    println!("Checking the files referenced by {} for missing files", get_connection_source_filepath(conn));
+    let mut file_records = RowIdSet::new();
 
+    for_each_file_record(conn, "", |record| {
+        if let Some(filepath) = &record.filepath {
+            let filepath = map_path(filepath, path_maps);
+            if !Path::new(&filepath).exists() {
+                if verbose {
+                    println!("Missing ID: {}, {}", record.id, filepath);
+                }
+                file_records.insert(record.id);
+            }
+        }
+        Ok(())
+    })?;
+
+    println!("Found {} records whose file is missing on disk", file_records.len());
     Ok(file_records)
 }
 
+// Searches the relocation folders for files with the same name as each orphan.  When several are found the one
+// sharing the most parent folder names with the old path wins.  A tie means the audio is on disk but it's unclear
+// which file is the record's, so those orphans are returned separately to be left alone.
+fn find_relocations(conn: &Connection, orphans: &RowIdSet, roots: &[String], path_maps: &[(String, String)], verbose: bool) -> Result<(HashMap<usize, PathBuf>, RowIdSet)> {
+    let mut relocations = HashMap::new();
+    let mut ambiguous = RowIdSet::new();
+    if orphans.is_empty() {
+        return Ok((relocations, ambiguous));
+    }
+
+    let mut missing: Vec<(usize, String, String)> = Vec::new();
+    for_each_file_record(conn, "", |record| {
+        if orphans.contains(record.id) {
+            missing.push((record.id, record.filename, map_path(&record.filepath.unwrap_or_default(), path_maps)));
+        }
+        Ok(())
+    })?;
+    let wanted: HashSet<&str> = missing.iter().map(|(_, filename, _)| filename.as_str()).collect();
+
+    println!("Searching {} for {} missing files", roots.join(", "), missing.len());
+    let mut found: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    let mut folders: Vec<PathBuf> = roots.iter().map(PathBuf::from).collect();
+    while let Some(folder) = folders.pop() {
+        let Ok(entries) = fs::read_dir(&folder) else {
+            println!("Can't read folder {}", folder.display());
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            // Symlinked folders aren't followed, so a link pointing back up the tree can't loop forever
+            let Ok(file_type) = entry.file_type() else { continue };
+            if file_type.is_dir() {
+                folders.push(path);
+            } else if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+                if wanted.contains(name) {
+                    found.entry(name.to_string()).or_default().push(path);
+                }
+            }
+        }
+    }
+
+    for (id, filename, filepath) in &missing {
+        let Some(candidates) = found.get(filename) else { continue };
+        let old_folders: Vec<_> = Path::new(filepath).parent().map(|parent| parent.iter().rev().collect()).unwrap_or_default();
+        let score = |candidate: &PathBuf| {
+            candidate.parent().map_or(0, |parent| parent.iter().rev().zip(&old_folders).take_while(|(a, b)| a == *b).count())
+        };
+        let best = candidates.iter().map(score).max().unwrap_or(0);
+        let mut best_candidates = candidates.iter().filter(|candidate| score(candidate) == best);
+        match (best_candidates.next(), best_candidates.next()) {
+            (Some(candidate), None) => {
+                if verbose {
+                    println!("Relocating ID: {}, {} -> {}", id, filepath, candidate.display());
+                }
+                relocations.insert(*id, candidate.clone());
+            }
+            _ => {
+                if verbose {
+                    println!("Several files named {} found for ID: {}, leaving the record alone", filename, id);
+                }
+                ambiguous.insert(*id);
+            }
+        }
+    }
+
+    println!(
+        "Relocated {} of {} missing files, {} had several matches and were left alone, {} weren't found",
+        relocations.len(), missing.len(), ambiguous.len(), missing.len() - relocations.len() - ambiguous.len()
+    );
+    Ok((relocations, ambiguous))
+}
 
-fn gather_filenames_with_tags(conn: &mut Connection, verbose: bool) -> Result<HashSet<FileRecord>> {
-    println!("Searching {} for filenames containing tags", get_connection_source_filepath(&conn));
-    let mut file_records = HashSet::new();
+// Points relocated records at their new file.  The pathname keeps its trailing separator like Soundminer writes it.
+fn apply_relocations(conn: &mut Connection, relocations: &HashMap<usize, PathBuf>, path_maps: &[(String, String)]) -> Result<()> {
+    let columns = get_table_columns(conn)?;
+    let has_pathname = columns.iter().any(|column| column.eq_ignore_ascii_case("pathname"));
 
-    let tags = get_tags(TAG_FILE_PATH)?;
+    let tx = conn.transaction()?;
+    {
+        let mut update_filepath = tx.prepare("UPDATE justinmetadata SET filepath = ? WHERE rowid = ?")?;
+        let mut update_pathname = if has_pathname { Some(tx.prepare("UPDATE justinmetadata SET pathname = ? WHERE rowid = ?")?) } else { None };
+        for (id, path) in relocations {
+            update_filepath.execute(rusqlite::params![unmap_path(&path.display().to_string(), path_maps), id])?;
+            if let Some(update_pathname) = &mut update_pathname {
+                let folder = path.parent().map(|parent| parent.join("")).unwrap_or_default();
+                update_pathname.execute(rusqlite::params![unmap_path(&folder.display().to_string(), path_maps), id])?;
+            }
+        }
+    }
+    tx.commit()?;
 
-    let mut tags_found: usize = 0;
-    for tag in tags {
-        let query = format!("SELECT rowid, filename, duration FROM justinmetadata WHERE filename LIKE '%' || ? || '%'");
-        let mut stmt = conn.prepare(&query)?;
-        let rows = stmt.query_map([tag.clone()], |row| {
-            Ok(FileRecord {
-                id: row.get(0)?,
-                filename: row.get(1)?,
-                duration: row.get(2)?,
-            })
+    println!("Updated the paths of {} relocated records", relocations.len());
+    Ok(())
+}
+
+
+// PATH REWRITE
+// Rewrites the filepath and pathname columns with the path mappings, so a database scanned on another
+// machine or mount points at the files where they are here.  Like a thinning run it works on a verified copy.
+fn rewrite_stored_paths(source_db_path: &str, config: &Config) -> Result<(), Box<dyn Error>> {
+    if config.path_maps.is_empty() {
+        return Err(format!("No path mappings to apply, add them with --map-path or {}", PATHS_FILE_PATH).into());
+    }
+    let started = get_timestamp();
+    let output_db_path = if config.safe {
+        let path = get_output_path(source_db_path, "remapped", &config.output, &config.name_template, &started);
+        check_overwrite(&path, config.force)?;
+        path
+    } else {
+        check_database_not_in_use(source_db_path)?;
+        source_db_path.to_string()
+    };
+    let temp_db_path = format!("{}.tmp", output_db_path);
+
+    let conn = open_database(source_db_path)?;
+    if !config.safe {
+        create_rotating_backup(&conn, source_db_path, config.keep_backups, &started).map_err(|e| database_in_use_error(e, source_db_path))?;
+    }
+    println!("Copying {} to working database {}", source_db_path, temp_db_path);
+    backup_database(&conn, &temp_db_path).map_err(|e| database_in_use_error(e, source_db_path))?;
+    drop(conn);
+
+    let mut conn = open_database(&temp_db_path)?;
+    let columns = get_table_columns(&conn)?;
+    let tx = conn.transaction()?;
+    let mut changed = 0;
+    for column in columns.iter().filter(|column| column.eq_ignore_ascii_case("filepath") || column.eq_ignore_ascii_case("pathname")) {
+        let mut paths: Vec<(usize, String)> = Vec::new();
+        {
+            let mut stmt = tx.prepare(&format!("SELECT rowid, \"{}\" FROM justinmetadata WHERE \"{}\" IS NOT NULL", column, column))?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let path = get_value_string(row, 1);
+                let mapped = map_path(&path, &config.path_maps);
+                if mapped != path {
+                    paths.push((row.get(0)?, mapped));
+                }
+            }
+        }
+        let mut update = tx.prepare(&format!("UPDATE justinmetadata SET \"{}\" = ? WHERE rowid = ?", column))?;
+        for (id, path) in &paths {
+            if config.verbose {
+                println!("ID {}: {} -> {}", id, column, path);
+            }
+            update.execute(rusqlite::params![path, id])?;
+        }
+        println!("Rewrote {} {} values", paths.len(), column);
+        changed += paths.len();
+    }
+    tx.commit()?;
+    drop(conn);
+
+    verify_database(&temp_db_path)?;
+    if !config.safe {
+        check_database_not_in_use(&output_db_path)?;
+    }
+    replace_database(&temp_db_path, &output_db_path)?;
+    println!("{} paths rewritten in {}", changed, output_db_path);
+    Ok(())
+}
+
+
+// PROTECT RULES
+// Unmarks every record matched by a rule in SMDupe_protect.txt, whichever search marked it.
+// Each record is credited to the first rule that protects it.
+fn apply_protect_rules(conn: &Connection, records: &mut RowIdSet, verbose: bool) -> Result<(), Box<dyn Error>> {
+    let rules = get_protect_rules(PROTECT_FILE_PATH)?;
+    if rules.is_empty() {
+        return Ok(());
+    }
+    println!("Applying {} protect rules from {}", rules.len(), PROTECT_FILE_PATH);
+
+    let mut total = 0;
+    for rule in &rules {
+        let (condition, value) = rule.condition();
+        let mut stmt = conn.prepare(&format!("SELECT rowid FROM justinmetadata WHERE {}", condition))?;
+        let mut rows = match value {
+            Some(value) => stmt.query([value])?,
+            None => stmt.query([])?,
+        };
+        let mut spared = 0;
+        while let Some(row) = rows.next()? {
+            let id: usize = row.get(0)?;
+            if records.remove(id) {
+                spared += 1;
+                if verbose {
+                    println!("Protected ID: {} ({})", id, rule.describe());
+                }
+            }
+        }
+        println!("    {}: spared {} records", rule.describe(), spared);
+        total += spared;
+    }
+    println!("Protect rules spared {} records", total);
+
+    Ok(())
+}
+
+
+// SAFETY LIMITS
+// A bad order rule or tag can mark most of a library, so the marked records are checked against the limits before anything happens.
+// Returns a line for every limit that was exceeded.
+fn check_safety_limits(conn: &Connection, records: &RowIdSet, reasons: &Reasons, total: usize, config: &Config) -> Result<Vec<String>> {
+    let mut exceeded = Vec::new();
+
+    if let Some(limit) = config.max_delete {
+        if limit.exceeded(records.len(), total) {
+            exceeded.push(format!("{} of {} records marked, over the --max-delete limit of {}", records.len(), total, limit.describe()));
+        }
+    }
+
+    // Every search is counted on its own, including the records an earlier search marked as well
+    if let Some(limit) = config.max_per_pass {
+        let passes = reasons.passes.iter().map(|(reason, pass_records)| (reason, pass_records.iter().filter(|&id| records.contains(id)).count()));
+        for (reason, count) in passes.filter(|(_, count)| limit.exceeded(*count, total)) {
+            exceeded.push(format!("{} records marked by '{}', over the --max-per-pass limit of {}", count, reason, limit.describe()));
+        }
+    }
+
+    if config.max_per_library.is_some() || config.max_per_show.is_some() {
+        // Marked and total records for every library and show
+        let mut libraries: HashMap<String, (usize, usize)> = HashMap::new();
+        let mut shows: HashMap<String, (usize, usize)> = HashMap::new();
+        for_each_file_record(conn, "", |record| {
+            let marked = usize::from(records.contains(record.id));
+            if let Some(library) = record.library {
+                let counts = libraries.entry(library).or_default();
+                counts.0 += marked;
+                counts.1 += 1;
+            }
+            if let Some(show) = record.show {
+                let counts = shows.entry(show).or_default();
+                counts.0 += marked;
+                counts.1 += 1;
+            }
+            Ok(())
         })?;
 
-        for file_record in rows {
-            let file_record = file_record?;
-            file_records.insert(file_record);
+        for (kind, limit, groups) in [("library", config.max_per_library, libraries), ("show", config.max_per_show, shows)] {
+            let Some(limit) = limit else { continue };
+            let mut groups: Vec<(String, (usize, usize))> = groups.into_iter().filter(|(_, (marked, count))| limit.exceeded(*marked, *count)).collect();
+            groups.sort();
+            for (name, (marked, count)) in groups {
+                exceeded.push(format!("{} of {} records marked in {} {}, over the --max-per-{} limit of {}", marked, count, kind, name, kind, limit.describe()));
+            }
+        }
+    }
+
+    Ok(exceeded)
+}
+
+
+// METADATA TRANSFER
+// Fills the metadata columns of each keeper from the duplicates being removed in its place
+fn transfer_metadata(conn: &mut Connection, state: &WorkState, records: &RowIdSet, policy: MetadataPolicy) -> Result<()> {
+    println!("Transferring metadata from removed duplicates to the records being kept");
+    let table_columns = get_table_columns(conn)?;
+    let columns: Vec<String> = get_metadata_columns(METADATA_FILE_PATH)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e)))?
+        .into_iter()
+        .filter_map(|column| {
+            let found = table_columns.iter().find(|c| c.eq_ignore_ascii_case(&column)).cloned();
+            if found.is_none() {
+                println!("Skipping metadata column {}, it is not in the database", column);
+            }
+            found
+        })
+        .collect();
+    if columns.is_empty() {
+        return Ok(());
+    }
+
+    let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+    for loser in records.iter() {
+        // A keeper can itself be a duplicate removed by a later search, so the chain is followed to the final keeper
+        if let Some(keeper) = resolve_keeper(state, records, loser)? {
+            groups.entry(keeper).or_default().push(loser);
         }
+    }
+
+    let select = format!("SELECT {} FROM justinmetadata WHERE rowid = ?", quote_columns(&columns));
+    let mut sorted_groups: Vec<(usize, Vec<usize>)> = groups.into_iter().collect();
+    sorted_groups.sort();
+
+    let mut changes = 0;
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare(&select)?;
+        let fetch = |stmt: &mut rusqlite::Statement, id: usize| -> Result<Vec<String>> {
+            stmt.query_row([id], |row| Ok((0..columns.len()).map(|i| get_value_string(row, i)).collect()))
+        };
+
+        for (keeper, mut losers) in sorted_groups {
+            losers.sort();
+            let original = fetch(&mut stmt, keeper)?;
+            let mut values = original.clone();
+
+            for loser in &losers {
+                let loser_values = fetch(&mut stmt, *loser)?;
+                for (i, loser_value) in loser_values.iter().enumerate() {
+                    let loser_value = loser_value.trim();
+                    if loser_value.is_empty() {
+                        continue;
+                    }
+                    // The keeper's own value is left exactly as it is unless something comes from the loser
+                    let value = values[i].trim();
+                    let merged = if value.is_empty() {
+                        Some(loser_value.to_string())
+                    } else {
+                        match policy {
+                            MetadataPolicy::Keep => None,
+                            MetadataPolicy::Concatenate if !value.contains(loser_value) => Some(format!("{}; {}", value, loser_value)),
+                            MetadataPolicy::Concatenate => None,
+                            MetadataPolicy::Longest if loser_value.chars().count() > value.chars().count() => Some(loser_value.to_string()),
+                            MetadataPolicy::Longest => None,
+                        }
+                    };
+                    if let Some(merged) = merged {
+                        values[i] = merged;
+                    }
+                }
+            }
 
-        if verbose && !file_records.is_empty() {
-            println!("Filenames found for tag '{}': {}", tag, file_records.len()-tags_found);
-            tags_found = file_records.len();
+            for (i, column) in columns.iter().enumerate() {
+                if values[i] != original[i] {
+                    println!("ID {}: {} '{}' -> '{}' (from IDs {:?})", keeper, column, original[i], values[i], losers);
+                    tx.execute(&format!("UPDATE justinmetadata SET \"{}\" = ? WHERE rowid = ?", column), rusqlite::params![values[i], keeper])?;
+                    changes += 1;
+                }
+            }
         }
     }
-    println!("{} total records containing tags marked for deletion", file_records.len());
-    Ok(file_records)
+    tx.commit()?;
+
+    println!("Transferred {} metadata values", changes);
+    Ok(())
 }
 
 
 // DELETE FUNCTION
-fn delete_file_records(conn: &mut Connection, records: &HashSet<FileRecord>, verbose: bool) -> Result<()> {
+// Each batch is committed on its own and checkpointed, so an interrupted run only loses the batch in progress
+fn delete_file_records(conn: &mut Connection, records: &RowIdSet, reasons: &Reasons, state: &mut WorkState, verbose: bool) -> Result<()> {
     let mut counter = 1;
     let total = records.len();
     println!("Removing Records Marked as Duplicates");
-    let tx = conn.transaction()?;
-
-    let mut sorted_records: Vec<_> = records.iter().collect();
-    sorted_records.sort_by(|a, b| b.id.cmp(&a.id));  // Sort by ID in descending order
 
-    sorted_records
-        .chunks(BATCH_SIZE)
-        .try_for_each(|chunk| {
-            if verbose {
-                for record in chunk {
-                    println!("\rDeleting ID: {}, Filename: {}", record.id, record.filename);
+    let mut ids = records.iter().rev();  // IDs in descending order
+    loop {
+        let chunk: Vec<usize> = ids.by_ref().take(BATCH_SIZE).collect();
+        if chunk.is_empty() {
+            break;
+        }
+        let placeholders = chunk.iter().map(|_| "?").collect::<Vec<_>>().join(", ");
+        let params: Vec<&dyn rusqlite::types::ToSql> = chunk.iter().map(|id| id as &dyn rusqlite::types::ToSql).collect();
+
+        if verbose {
+            let ids = chunk.iter().map(usize::to_string).collect::<Vec<_>>().join(", ");
+            for_each_file_record(conn, &format!("WHERE rowid IN ({}) ORDER BY rowid DESC", ids), |record| {
+                match reasons.get(record.id) {
+                    Some(reason) => println!("\rDeleting ID: {}, Filename: {} [{}] ({})", record.id, record.filename, record.describe(), reason),
+                    None => println!("\rDeleting ID: {}, Filename: {} [{}]", record.id, record.filename, record.describe()),
                 }
-            } else {
-                let _ = io::stdout().flush();
-                print!("\r{} / {}", counter, total);
-                counter += BATCH_SIZE;
-            }
-            let placeholders = chunk.iter().map(|_| "?").collect::<Vec<_>>().join(", ");
-            let query = format!("DELETE FROM justinmetadata WHERE rowid IN ({})", placeholders);
-            let params: Vec<&dyn rusqlite::types::ToSql> = chunk.iter().map(|record| &(record.id) as &dyn rusqlite::types::ToSql).collect();
-            tx.execute(&query, params.as_slice()).map(|_| ())
-    })?;
+                Ok(())
+            })?;
+        } else {
+            let _ = io::stdout().flush();
+            print!("\r{} / {}", counter, total);
+            counter += BATCH_SIZE;
+        }
+        let query = format!("DELETE FROM justinmetadata WHERE rowid IN ({})", placeholders);
+
+        let tx = conn.transaction()?;
+        tx.execute(&query, params.as_slice())?;
+        tx.commit()?;
+        state.mark_deleted(&chunk)?;
+    }
 
     println!("\r{} / {}", total, total);
-    tx.commit()?;
 
     Ok(())
 }
 
+// SEARCH PASSES
+fn gather_pass(pass: &Pass, conn: &mut Connection, config: &Config) -> Result<PassResult> {
+    let started = Instant::now();
+    // --group-all extends the column grouping to the compare and tag passes
+    let pass_group = if config.group_all { config.group_sort.clone() } else { None };
+    let mut keepers = HashMap::new();
+
+    let records = match pass {
+        Pass::Compare { path, .. } => {
+            let compare_conn = Connection::open(path)?;
+            gather_compare_database_overlaps(conn, &compare_conn, config.compare_key.unwrap_or(CompareKey::Filename), &pass_group, config.group_null, &config.path_maps)?
+        }
+        Pass::Filename => gather_duplicate_filenames_in_database(conn, &config.group_sort, config.group_null, config.duration_tolerance, &mut keepers, config.verbose)?,
+        Pass::Tags => gather_filenames_with_tags(conn, &pass_group, config.group_null, config.verbose)?,
+        Pass::DeepDive => gather_records_with_trailing_numbers(conn, &config.group_sort, config.group_null, config.duration_tolerance, &mut keepers, config.verbose)?,
+        Pass::Orphans => gather_orphaned_records(conn, &config.path_maps, config.verbose)?,
+    };
+
+    Ok(PassResult { records, keepers, elapsed: Some(started.elapsed()) })
+}
+
+// Each search gets its own read-only connection to the same database file, which nothing else writes while they run
+fn gather_passes_in_parallel(passes: &[Pass], db_path: &str, config: &Config) -> Result<Vec<PassResult>> {
+    thread::scope(|scope| {
+        let handles: Vec<_> = passes
+            .iter()
+            .map(|pass| {
+                scope.spawn(move || {
+                    let mut conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+                    conn.busy_timeout(BUSY_TIMEOUT)?;
+                    gather_pass(pass, &mut conn, config)
+                })
+            })
+            .collect();
+        handles.into_iter().map(|handle| handle.join().expect("search thread panicked")).collect()
+    })
+}
+
 
 fn main() -> Result<(), Box<dyn Error>> {
     println!("SMDupeRemover v{}", VERSION);
 
     let args: Vec<String> = env::args().collect();
-    let config = Config::new(&args)?;
+    let mut config = Config::new(&args)?;
+
+    let mut source_db_path = config.target_db.clone().unwrap();
+
+    // Mappings from the file come first, --map-path ones are added after them
+    let mut path_maps = get_path_maps(PATHS_FILE_PATH)?;
+    path_maps.append(&mut config.path_maps);
+    config.path_maps = path_maps;
 
-    let source_db_path = &config.target_db.unwrap();
+    if config.rewrite_paths {
+        return rewrite_stored_paths(&source_db_path, &config);
+    }
+
+    if let Some(diff_db_path) = &config.diff_db {
+        let conn_a = Connection::open(&source_db_path)?;
+        let conn_b = Connection::open(diff_db_path)?;
+        diff::diff_databases(&conn_a, &conn_b, config.compare_key.unwrap_or(CompareKey::Path), &config.path_maps, config.verbose)?;
+        return Ok(());
+    }
+
+    if !config.audit_roots.is_empty() {
+        let conn = Connection::open(&source_db_path)?;
+        audit::audit_disk(&conn, &config.audit_roots, &config.path_maps, config.flag_known_names, config.verbose)?;
+        return Ok(());
+    }
+
+    // Searches and deletion progress are checkpointed so an interrupted run can be resumed
+    let mut state = WorkState::open(&source_db_path, config.resume)?;
+    let settings = get_search_settings(&config)?;
+    if let Some(previous) = state.get("search_settings")? {
+        if previous != settings {
+            for (before, now) in previous.lines().zip(settings.lines()).filter(|(before, now)| before != now) {
+                println!("Unfinished run used {}, this run uses {}", before, now);
+            }
+            return Err("The unfinished run used different search settings, run without --resume to start over".into());
+        }
+    }
+    state.set("search_settings", &settings)?;
+
+    // Every output name is worked out and checked up front so nothing is overwritten by surprise.
+    // A resumed run keeps the names chosen by the run it continues.
+    let started = get_timestamp();
+    let merged_db_path = get_output_path(&source_db_path, "merged", &config.output, &config.name_template, &started);
+    let work_db_path = match state.get("work_db_path")? {
+        Some(path) => path,
+        None => get_output_path(&source_db_path, "thinned", &config.output, &config.name_template, &started),
+    };
+    if config.merge_db.is_some() {
+        check_overwrite(&merged_db_path, config.force)?;
+    } else if config.safe {
+        check_overwrite(&work_db_path, config.force)?;
+    }
+    let dupes_source = if config.merge_db.is_some() { &merged_db_path } else { &source_db_path };
+    let duplicate_db_path = match state.get("duplicate_db_path")? {
+        Some(path) => path,
+        None => get_output_path(dupes_source, "dupes", &config.dupes_output, &config.name_template, &started),
+    };
+    let dupes_done = state.get("dupes_done")?.is_some();
+    if config.duplicate_db && !dupes_done {
+        check_overwrite(&duplicate_db_path, config.force)?;
+    }
+    state.set("work_db_path", &work_db_path)?;
+    state.set("duplicate_db_path", &duplicate_db_path)?;
+
+    // The merged database is a new file, so the passes below work on it directly
+    if let Some(merge_db_path) = &config.merge_db {
+        diff::merge_databases(&source_db_path, merge_db_path, &merged_db_path)?;
+        source_db_path = merged_db_path;
+        config.safe = false;
+    }
+
+    let source_db_path = &source_db_path;
+    // Unsafe runs write to the database itself, so make sure nothing else (like Soundminer) has it open
+    if !config.safe && config.merge_db.is_none() {
+        check_database_not_in_use(source_db_path)?;
+    }
     println!("Opening {}", source_db_path);
-    let mut conn = Connection::open(&source_db_path)?; 
+    let mut conn = open_database(source_db_path)?; 
 
     let total_records = get_db_size(&conn);
     println!("{} Total Records found in {}", total_records, source_db_path);
   
-    let mut all_ids_to_delete = HashSet::<FileRecord>::new();
+    let mut all_ids_to_delete = RowIdSet::new();
 
-    if let Some(compare_db_path) = config.compare_db {
-        let compare_conn = Connection::open(&compare_db_path)?; 
-        let ids_from_compare_db = gather_compare_database_overlaps(&conn, &compare_conn)?;
-        all_ids_to_delete.extend(ids_from_compare_db);
-    }
+    // All changes happen in a verified temporary copy which only replaces the output once everything succeeded
+    let output_db_path = if config.safe { work_db_path.clone() } else { source_db_path.clone() };
+    let temp_db_path = format!("{}.tmp", output_db_path);
+    let resume_deletion = state.get("phase")?.as_deref() == Some("deleting") && Path::new(&temp_db_path).exists();
 
+    let mut passes: Vec<Pass> = Vec::new();
+    for compare_db_path in &config.compare_dbs {
+        let reference = get_connection_source_filepath(&Connection::open(compare_db_path)?);
+        passes.push(Pass::Compare { path: compare_db_path.clone(), reference });
+    }
     if config.filename_check {
-        let ids_from_duplicates = gather_duplicate_filenames_in_database(&mut conn, &config.group_sort, config.group_null, config.verbose)?;
-        all_ids_to_delete.extend(ids_from_duplicates);
+        passes.push(Pass::Filename);
     }
-
     if config.prune_tags {
-        let ids_from_tags = gather_filenames_with_tags(&mut conn, config.verbose)?;
-        all_ids_to_delete.extend(ids_from_tags);
+        passes.push(Pass::Tags);
     }
-    
     if config.numbers_check {
-        let number_dupes = gather_records_with_trailing_numbers(&mut conn, total_records)?;
-        all_ids_to_delete.extend(number_dupes);
+        passes.push(Pass::DeepDive);
+    }
+    if config.orphans {
+        passes.push(Pass::Orphans);
+    }
+
+    // Searches an interrupted run already finished are restored instead of run again
+    let mut results: Vec<Option<PassResult>> = Vec::new();
+    let mut pending: Vec<Pass> = Vec::new();
+    for pass in &passes {
+        if state.pass_completed(&pass.name())? {
+            let records = state.load_pass(&pass.name())?;
+            println!("Skipping {} search, {} records restored from the unfinished run", pass.name(), records.len());
+            results.push(Some(PassResult { records, keepers: HashMap::new(), elapsed: None }));
+        } else {
+            results.push(None);
+            pending.push(pass.clone());
+        }
+    }
+
+    // Parallel searches read the working copy, so they all see the same snapshot of the database.
+    // A working copy with deletions in progress is never replaced, the deletion state only matches that copy.
+    let snapshot = config.parallel && pending.len() > 1 && !resume_deletion;
+    let searches_started = Instant::now();
+    let mut gathered = if snapshot {
+        println!("Copying {} to working database {} to search it in parallel", source_db_path, temp_db_path);
+        backup_database(&conn, &temp_db_path).map_err(|e| database_in_use_error(e, source_db_path))?;
+        gather_passes_in_parallel(&pending, &temp_db_path, &config)?
+    } else {
+        let mut gathered = Vec::new();
+        for pass in &pending {
+            gathered.push(gather_pass(pass, &mut conn, &config)?);
+        }
+        gathered
+    }
+    .into_iter();
+    let searches_elapsed = searches_started.elapsed();
+
+    // Each removed record is attributed to the first search (or reference database) that marked it
+    let mut reasons = Reasons::default();
+    let mut compare_summary: Vec<(String, usize, usize)> = Vec::new();
+    let mut timings: Vec<(String, Option<Duration>)> = Vec::new();
+
+    for (pass, result) in passes.iter().zip(results) {
+        let result = match result {
+            Some(result) => result,
+            None => gathered.next().expect("a result for every search"),
+        };
+        if let Pass::Compare { reference, .. } = pass {
+            let new_records = result.records.iter().filter(|&id| reasons.get(id).is_none()).count();
+            compare_summary.push((reference.clone(), result.records.len(), new_records));
+        }
+        // Records removed by the filename and deep dive searches point at the record kept in their place
+        if result.elapsed.is_some() {
+            state.save_pass(&pass.name(), &result.records, &pass.reason(), &result.keepers)?;
+        }
+        timings.push((pass.name(), result.elapsed));
+        all_ids_to_delete.union_with(&result.records);
+        reasons.add(pass.reason(), result.records);
+    }
+
+    if compare_summary.len() > 1 {
+        println!("Overlaps by reference database:");
+        for (reference, found, new_records) in &compare_summary {
+            println!("    {}: {} overlapping records, {} not found in an earlier reference", reference, found, new_records);
+        }
+    }
+
+    if !timings.is_empty() {
+        println!("Search times:");
+        for (name, elapsed) in &timings {
+            match elapsed {
+                Some(elapsed) => println!("    {}: {:.2?}", name, elapsed),
+                None => println!("    {}: restored", name),
+            }
+        }
+        if snapshot {
+            println!("    all searches in parallel: {:.2?}", searches_elapsed);
+        }
+    }
+
+    // Orphans found again under a relocation folder get their path updated instead of being deleted, orphans matching
+    // several files equally well are left alone
+    let mut relocations = HashMap::new();
+    if !config.relocate_roots.is_empty() {
+        let orphan_reason = Pass::Orphans.reason();
+        let orphans: RowIdSet = all_ids_to_delete.iter().filter(|&id| reasons.get(id) == Some(orphan_reason.as_str())).collect();
+        let ambiguous;
+        (relocations, ambiguous) = find_relocations(&conn, &orphans, &config.relocate_roots, &config.path_maps, config.verbose)?;
+        for id in relocations.keys().copied().chain(ambiguous.iter()) {
+            all_ids_to_delete.remove(id);
+        }
+    }
+
+    // Protect rules can unmark one file of a channel set, so the sets are checked for completeness afterwards
+    apply_protect_rules(&conn, &mut all_ids_to_delete, config.verbose)?;
+    spare_partial_channel_sets(&conn, &mut all_ids_to_delete, config.verbose)?;
+
+    let summary = report::summarize(&conn, &all_ids_to_delete, &reasons, &config.path_maps, config.file_check)?;
+    summary.print(config.verbose);
+    if let Some(report_path) = &config.report {
+        summary.write_json(report_path, source_db_path, total_records)?;
+        println!("Report written to {}", report_path);
     }
 
-    if all_ids_to_delete.is_empty() {
+    let exceeded = check_safety_limits(&conn, &all_ids_to_delete, &reasons, total_records, &config)?;
+    if !exceeded.is_empty() {
+        println!("Safety limits exceeded:");
+        for line in &exceeded {
+            println!("    {}", line);
+        }
+        if !config.ignore_limits {
+            // Nothing was deleted, so nothing the run created is left behind to block the next one
+            if snapshot {
+                fs::remove_file(&temp_db_path)?;
+            }
+            if config.merge_db.is_some() {
+                drop(conn);
+                fs::remove_file(source_db_path)?;
+            }
+            state.finish()?;
+            return Err("Nothing was deleted.  Check your order rules and tags, or rerun with --ignore-limits if this is expected".into());
+        }
+        println!("Continuing anyway because of --ignore-limits");
+    }
+
+    if all_ids_to_delete.is_empty() && relocations.is_empty() {
         println!("No files to delete.");
+        if snapshot {
+            fs::remove_file(&temp_db_path)?;
+        }
+        state.finish()?;
         return Ok(());
     }
 
     print!("Found {} total records to delete. ", all_ids_to_delete.len());
+    if !relocations.is_empty() {
+        print!("{} relocated paths to update. ", relocations.len());
+    }
     if config.prompt {
         println!(" Type 'yes' to confirm deletion: ");
         let mut user_input = String::new();
         io::stdin().read_line(&mut user_input)?;
         if user_input.trim().to_lowercase() != "yes" {
             println!("Deletion aborted.");
+            if snapshot {
+                fs::remove_file(&temp_db_path)?;
+            }
             return Ok(());
         }
 
     } 
     
-    if config.duplicate_db && all_ids_to_delete.len() > 0 {
-        create_duplicates_db(&source_db_path, &all_ids_to_delete)?;
+    if config.duplicate_db && !dupes_done {
+        create_duplicates_db(source_db_path, &duplicate_db_path, &all_ids_to_delete, &state, &reasons, &config.dupes_note_column)?;
+        state.set("dupes_done", "1")?;
     }
-    
-    let work_db_path = format!("{}_thinned.sqlite", &source_db_path.trim_end_matches(".sqlite"));
-    if config.safe {
-        println!("Backing up {}", source_db_path);
-        fs::copy(&source_db_path, &work_db_path)?;
-        conn = Connection::open(&work_db_path).unwrap();
+
+    if resume_deletion {
+        println!("Resuming deletion in working database {}", temp_db_path);
+    } else {
+        // Unsafe runs replace the target, so a verified backup of it is kept first
+        if !config.safe && config.merge_db.is_none() {
+            create_rotating_backup(&conn, source_db_path, config.keep_backups, &started).map_err(|e| database_in_use_error(e, source_db_path))?;
+        }
+
+        if !snapshot {
+            println!("Copying {} to working database {}", source_db_path, temp_db_path);
+            backup_database(&conn, &temp_db_path).map_err(|e| database_in_use_error(e, source_db_path))?;
+        }
+
+        if config.transfer_metadata {
+            let mut work_conn = open_database(&temp_db_path)?;
+            transfer_metadata(&mut work_conn, &state, &all_ids_to_delete, config.metadata_policy)?;
+        }
+        if !relocations.is_empty() {
+            let mut work_conn = open_database(&temp_db_path)?;
+            apply_relocations(&mut work_conn, &relocations, &config.path_maps)?;
+        }
+        state.set("phase", "deleting")?;
     }
+    drop(conn);
+    let mut conn = open_database(&temp_db_path)?;
+
     println!("Proceeding with deletion."); 
     
-    delete_file_records(&mut conn, &all_ids_to_delete, config.verbose)?;
+    let remaining_ids = state.remaining(&all_ids_to_delete)?;
+    delete_file_records(&mut conn, &remaining_ids, &reasons, &mut state, config.verbose)?;
     vacuum_db(&conn)?;
+    drop(conn);
+    verify_database(&temp_db_path)?;
+    if !config.safe && config.merge_db.is_none() {
+        check_database_not_in_use(&output_db_path)?;
+    }
+    replace_database(&temp_db_path, &output_db_path)?;
+    state.finish()?;
     println!("Removed {} records.", all_ids_to_delete.len());
 
 
     if config.safe {
         println!("Thinned records database moved to: {}", work_db_path);
+    } else if config.merge_db.is_some() {
+        println!("Merged database {} sucessfully thinned", source_db_path);
     } else {    
         println!("Database {} sucessfully thinned", source_db_path);
     }
@@ -700,8 +2592,123 @@ fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+// LOCK FUNCTIONS
+fn open_database(path: &str) -> Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    Ok(conn)
+}
+
+// Soundminer keeps its database open while running.  A hot journal, a non-empty write-ahead log
+// or a lock that blocks exclusive access all mean another program is using it.
+// In WAL mode every open connection holds a shared lock, even an idle one, but BEGIN EXCLUSIVE only waits for writers
+// there.  Exclusive locking mode has to lock the file itself, so it's refused while any other connection is open.
+// An idle program in rollback journal mode holds no lock at all and can't be detected.
+fn check_database_not_in_use(db_path: &str) -> Result<(), Box<dyn Error>> {
+    for suffix in ["-journal", "-wal"] {
+        let path = format!("{}{}", db_path, suffix);
+        if fs::metadata(&path).map(|m| m.len() > 0).unwrap_or(false) {
+            return Err(format!("Database {} is in use ({} exists).  Close it in Soundminer and try again.", db_path, path).into());
+        }
+    }
+
+    let conn = Connection::open(db_path)?;
+    conn.busy_timeout(Duration::from_millis(500))?;
+    conn.execute_batch("PRAGMA locking_mode = EXCLUSIVE; BEGIN EXCLUSIVE; ROLLBACK;")
+        .map_err(|e| database_in_use_error(e.into(), db_path))?;
+    Ok(())
+}
+
+fn is_busy_error(e: &rusqlite::Error) -> bool {
+    matches!(e, rusqlite::Error::SqliteFailure(err, _) if err.code == ErrorCode::DatabaseBusy || err.code == ErrorCode::DatabaseLocked)
+}
+
+// Turns SQLite busy/locked failures into a clear message, anything else is passed through
+fn database_in_use_error(e: Box<dyn Error>, db_path: &str) -> Box<dyn Error> {
+    match e.downcast_ref::<rusqlite::Error>() {
+        Some(sqlite_error) if is_busy_error(sqlite_error) => {
+            format!("Database {} is in use by another program.  Close it in Soundminer and try again.", db_path).into()
+        }
+        _ => e,
+    }
+}
+
+// BACKUP FUNCTIONS
+// Copies through SQLite's online backup API so pages still in the write-ahead log are included, then verifies the copy
+fn backup_database(conn: &Connection, destination_path: &str) -> Result<(), Box<dyn Error>> {
+    if Path::new(destination_path).exists() {
+        fs::remove_file(destination_path)?;
+    }
+    conn.backup(DatabaseName::Main, destination_path, None)?;
+    verify_database(destination_path)
+}
+
+fn verify_database(path: &str) -> Result<(), Box<dyn Error>> {
+    let conn = Connection::open(path)?;
+    let result: String = conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+    if result != "ok" {
+        return Err(format!("Integrity check of {} failed: {}", path, result).into());
+    }
+    Ok(())
+}
+
+// Atomically moves the finished temporary database into place
+fn replace_database(temp_db_path: &str, db_path: &str) -> Result<(), Box<dyn Error>> {
+    // A leftover write-ahead log would be replayed onto the new file the next time it's opened
+    let wal_path = format!("{}-wal", db_path);
+    if fs::metadata(&wal_path).map(|m| m.len() > 0).unwrap_or(false) {
+        return Err(format!("{} has an active write-ahead log, is it open in Soundminer?  Changes were left in {}", db_path, temp_db_path).into());
+    }
+    fs::rename(temp_db_path, db_path)?;
+    Ok(())
+}
+
+// Keeps the newest `keep` timestamped backups of the database next to it
+fn create_rotating_backup(conn: &Connection, db_path: &str, keep: usize, started: &(String, String)) -> Result<(), Box<dyn Error>> {
+    let backup_path = get_output_path(db_path, "backup", &None, "{name}_{suffix}_{timestamp}{ext}", started);
+    println!("Backing up {} to {}", db_path, backup_path);
+    backup_database(conn, &backup_path)?;
+
+    let source = Path::new(db_path);
+    let folder = match source.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => Path::new(".").to_path_buf(),
+    };
+    let name = source.file_stem().unwrap_or_default().to_string_lossy().to_string();
+    let ext = source.extension().map(|ext| ext.to_string_lossy().to_string());
+
+    // Only backups this tool wrote are rotated, a hand made Library_backup_before_import.sqlite is left alone
+    let mut backups: Vec<_> = fs::read_dir(&folder)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_stem().is_some_and(|stem| is_rotating_backup_name(&stem.to_string_lossy(), &name))
+                && path.extension().map(|e| e.to_string_lossy().to_string()) == ext
+        })
+        .collect();
+    backups.sort();
+
+    let excess = backups.len().saturating_sub(keep.max(1));
+    for old_backup in backups.iter().take(excess) {
+        println!("Removing old backup {}", old_backup.display());
+        fs::remove_file(old_backup)?;
+    }
+    Ok(())
+}
+
+// True for {name}_backup_YYYY-MM-DD_HHMMSS, the name create_rotating_backup() gives its backups
+fn is_rotating_backup_name(stem: &str, name: &str) -> bool {
+    let Some(timestamp) = stem.strip_prefix(name).and_then(|rest| rest.strip_prefix("_backup_")) else { return false };
+    timestamp.len() == 17
+        && timestamp.char_indices().all(|(index, c)| match index {
+            4 | 7 => c == '-',
+            10 => c == '_',
+            _ => c.is_ascii_digit(),
+        })
+}
+
 fn vacuum_db(conn: &Connection) -> Result<()> { 
-    println!("Cleaning up Database {}", get_connection_source_filepath(&conn));
+    println!("Cleaning up Database {}", get_connection_source_filepath(conn));
     conn.execute("VACUUM", [])?; // Execute VACUUM on the database
     Ok(())
 }
@@ -714,16 +2721,44 @@ Usage: SMDupeRemover <database> [options]
 Options:
     -a, --all                         Do all the things: Standard dupliate search, tag search, deep dive, create duplicate database
     -A,                               Same as '-ay' do all the things and no prompt for deletions
-    -c, --compare <database>          Compare with another database
-    -d, --create-duplicates-database  Generates an additional _dupes database of all files that were removed
+    -c, --compare <database>          Compare with another database or a folder of databases.  Can be used multiple times
+        --compare-key <key>           How records are matched when comparing: filename (default), duration, format, normalized, hash or path
+    -d, --create-duplicates-database  Generates an additional _dupes database of the files removed and the files kept in their place
+        --dupes-output <path>         Path or folder for the _dupes database.  Implies -d
+        --dupes-note-column <column>  Also write the keep/remove notes into this column of the _dupes database.  Implies -d
     -D, --deep-dive                   Perform a 'deep dive'duplicates search.  Looking for similar files with .1 or .M before the extension
-        --generate-config-files       Generate default config files (SMDupe_order.txt and SMDupe_tags.txt)
+        --generate-config-files       Generate default config files (SMDupe_order.txt, SMDupe_tags.txt and SMDupe_metadata.txt)
+    -f, --force                       Overwrite existing output databases
     -g, --group <column>              Search for Duplicates within the specified column groupings.  NULL column records skipped
         --group-null <column>         Search for Duplicates within the specified column groupings.  NULL column records processed together
+    -G, --group-all                   Also apply the group column to the compare and tag passes
+        --diff <database>             Report records only in the target, only in <database> and in both with column differences
+        --merge <database>            Build a _merged database of both, then remove duplicates from it with the usual searches
     -h, --help                        Display this help message
     -i,                               used in conjunction with -s or -l to include null grouping
+        --report <file.json>          Write the breakdown of marked records and reclaimable space as JSON
+        --no-file-check               Take sizes from the database without checking marked files on disk (slow volumes)
+        --max-delete <N|N%>           Abort if more than this many records (or percent of the database) are marked
+        --max-per-library <N|N%>      Abort if more than this many records (or percent) of any one library are marked
+        --max-per-show <N|N%>         Abort if more than this many records (or percent) of any one show are marked
+        --max-per-pass <N|N%>         Abort if any one search marks more than this many records (or percent of the database)
+        --ignore-limits               Delete even when a safety limit is exceeded
+        --map-path <from=to>          Translate stored path prefixes to where the files are on this machine (repeatable)
+        --rewrite-paths               Rewrite the paths stored in the database using the path mappings
+        --audit-disk <folder>         List audio files in this folder (repeatable) that no record points at, then exit
+        --flag-known-names            With --audit-disk, flag unlisted files whose filename is already in the database
+        --orphans                     Remove records whose file is missing on disk
+        --relocate <folder>           Look for missing files in this folder (repeatable) and update their path instead
+        --duration-tolerance <N|N%>   Only treat same-name records as duplicates when their durations are this close
+        --parallel                    Run the searches at the same time on a snapshot of the database
+        --resume                      Continue an interrupted run, skipping finished searches and deleted batches
+        --keep-backups <count>        Number of timestamped backups kept for --unsafe runs (default 5)
     -l, --group-by-library            Search for duplicates within each Library. Untagged Library files skipped
+    -m, --transfer-metadata           Fill empty metadata on kept records from the duplicates removed (columns in SMDupe_metadata.txt)
+        --metadata-policy <policy>    When both have a value: keep (default), concat or longest.  Implies -m
+        --name-template <template>    Naming for output databases.  Default {name}_{suffix}{ext}, also {date}, {time}, {timestamp}
     -n, --no-filename-check           Skips searching for filename duplicates in main database
+    -o, --output <path>               Path or folder for the thinned (or merged) database
     -s, --group-by-show               Search for duplicates within each show. Untagged Show files skipped
     -t, --prune-tags                  Remove Files with Specified Tags in SMDupe_tags.txt or use defaults
     -u, --unsafe                      WRITES DIRECTLY TO TARGET DATABASE with NO PROMPT
@@ -769,6 +2804,35 @@ fn generate_config_files(tjf: bool) -> Result<()> {
         writeln!(tags_file, ".new.").unwrap();
     }
     println!("Created {} with default tags.", TAG_FILE_PATH);
+
+    let mut metadata_file = File::create(METADATA_FILE_PATH).unwrap();
+    writeln!(metadata_file, "## Columns copied from removed duplicates to the record being kept with --transfer-metadata.  Hashtag will bypass").unwrap();
+    for column in DEFAULT_METADATA_COLUMNS {
+        writeln!(metadata_file, "{}", column).unwrap();
+    }
+    println!("Created {} with default columns.", METADATA_FILE_PATH);
+
+    let mut protect_file = File::create(PROTECT_FILE_PATH).unwrap();
+    writeln!(protect_file, "## Records matching any of these rules are never deleted, whichever search marked them.  Hashtag will bypass").unwrap();
+    writeln!(protect_file, "## ").unwrap();
+    writeln!(protect_file, "## Examples:").unwrap();
+    writeln!(protect_file, "## path:/TJF RECORDINGS/").unwrap();
+    writeln!(protect_file, "## ^----- Anything with /TJF RECORDINGS/ in its file path").unwrap();
+    writeln!(protect_file, "## path:/Volumes/Library/*.aif").unwrap();
+    writeln!(protect_file, "## ^----- With * or ? the whole file path has to match the pattern").unwrap();
+    writeln!(protect_file, "## library:Boom Library").unwrap();
+    writeln!(protect_file, "## show:My Show").unwrap();
+    writeln!(protect_file, "## sql:Description LIKE '%hero%'").unwrap();
+    writeln!(protect_file, "## ^----- Any SQL condition on the justinmetadata columns").unwrap();
+    println!("Created {} with example rules.", PROTECT_FILE_PATH);
+
+    let mut paths_file = File::create(PATHS_FILE_PATH).unwrap();
+    writeln!(paths_file, "## Path prefixes to translate when looking at files on this machine, as stored=local.  Hashtag will bypass").unwrap();
+    writeln!(paths_file, "## Used to find files on disk (--orphans, --relocate, hashing, disk space) and by --rewrite-paths").unwrap();
+    writeln!(paths_file, "## ").unwrap();
+    writeln!(paths_file, "## Example:").unwrap();
+    writeln!(paths_file, "## /Volumes/SFX=/mnt/sfx").unwrap();
+    println!("Created {} with an example mapping.", PATHS_FILE_PATH);
     Ok(())
 }
 
@@ -784,7 +2848,7 @@ fn create_order_file() -> Result<()> {
     writeln!(order_file, "## CASE WHEN pathname LIKE '%RECORD%' THEN 0 ELSE 1 END ASC").unwrap();
     writeln!(order_file, "## ^----- Records with RECORD (not case sensitive) in the path will be kept over records without").unwrap();
     writeln!(order_file, "## ").unwrap();
-    writeln!(order_file, "").unwrap();
+    writeln!(order_file).unwrap();
     for field in &DEFAULT_ORDER {
         writeln!(order_file, "{}", field).unwrap();
     }
@@ -862,3 +2926,37 @@ fn count_total_duplicate_filenames(conn: &Connection) -> Result<usize> {
 
     Ok(count)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duration_clusters_read_non_text_groups() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("
+            CREATE TABLE justinmetadata (filename TEXT, duration TEXT, Disc INTEGER);
+            INSERT INTO justinmetadata VALUES ('Door.wav', '00:00:01.000', 1);
+            INSERT INTO justinmetadata VALUES ('Door.wav', '00:00:01.200', 1);
+            INSERT INTO justinmetadata VALUES ('Door.wav', '00:00:05.000', 1);
+            INSERT INTO justinmetadata VALUES ('Door.wav', '00:00:01.000', 2);
+            INSERT INTO justinmetadata VALUES ('Door.wav', '00:00:01.000', NULL);
+        ").unwrap();
+
+        let group = Some("Disc".to_string());
+        let (clusters, removable) = get_duration_clusters(&conn, &group, "", "filename", DurationTolerance::Seconds(0.5), false).unwrap();
+        assert_eq!(clusters.len(), 5);
+        assert_eq!(clusters[&1], clusters[&2]);
+        assert_ne!(clusters[&1], clusters[&3]);
+        // Disc 1 keeps one of each sound, disc 2 and the NULL disc keep their only record
+        assert_eq!(removable, 1);
+    }
+
+    #[test]
+    fn only_timestamped_backups_rotate() {
+        assert!(is_rotating_backup_name("Library_backup_2026-10-18_093015", "Library"));
+        assert!(!is_rotating_backup_name("Library_backup_before_import", "Library"));
+        assert!(!is_rotating_backup_name("Library_backup_2026-10-18_093015_copy", "Library"));
+        assert!(!is_rotating_backup_name("Library_2_backup_2026-10-18_093015", "Library"));
+    }
+}
@@ -0,0 +1,237 @@
+use rusqlite::{Connection, Result};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+
+use crate::{for_each_file_record, map_path, FileRecord, Reasons, RowIdSet};
+
+// Rows shown per breakdown table unless --verbose is on
+const TABLE_ROWS: usize = 10;
+// Folders below the root used to group records, /Volumes/Library/SFX/Doors/door.wav counts towards /Volumes/Library
+const TOP_FOLDER_DEPTH: usize = 2;
+
+#[derive(Default)]
+struct Row {
+    name: String,
+    records: usize,
+    bytes: u64,
+    missing: usize,
+}
+
+// Marked records broken down by pass, library, show, top folder and format, with the disk space they take up
+// and the records whose file is no longer on disk
+pub struct Summary {
+    records: usize,
+    bytes: u64,
+    unknown_size: usize,
+    missing: Vec<(usize, String)>,
+    files_checked: bool,
+    breakdowns: Vec<(&'static str, Vec<Row>)>,
+}
+
+// Where a marked record's file stands on disk
+enum FileSize {
+    Known(u64),
+    Unknown,
+    Missing,
+}
+
+// SUMMARY
+// check_files looks every marked file up on disk to find the missing ones.  Without it (--no-file-check) the disk is only
+// touched for records the size column has no size for, since a full scan of a large library on a network mount is slow.
+pub fn summarize(conn: &Connection, records: &RowIdSet, reasons: &Reasons, path_maps: &[(String, String)], check_files: bool) -> Result<Summary> {
+    let titles = ["pass", "library", "show", "folder", "format"];
+    let mut groups: Vec<HashMap<String, Row>> = titles.iter().map(|_| HashMap::new()).collect();
+    let mut summary = Summary { records: 0, bytes: 0, unknown_size: 0, missing: Vec::new(), files_checked: check_files, breakdowns: Vec::new() };
+
+    for_each_file_record(conn, "", |mut record| {
+        if !records.contains(record.id) {
+            return Ok(());
+        }
+        // Folders, sizes and missing files are all worked out from where the file is on this machine
+        record.filepath = record.filepath.map(|filepath| map_path(&filepath, path_maps));
+        record.pathname = record.pathname.map(|pathname| map_path(&pathname, path_maps));
+        let size = get_file_size(&record, check_files);
+        summary.records += 1;
+        match size {
+            FileSize::Known(bytes) => summary.bytes += bytes,
+            FileSize::Unknown => summary.unknown_size += 1,
+            FileSize::Missing => summary.missing.push((record.id, record.filepath.clone().unwrap_or_default())),
+        }
+
+        let names = [
+            reasons.get(record.id).unwrap_or("unknown").to_string(),
+            record.library.clone().unwrap_or_else(|| "(none)".to_string()),
+            record.show.clone().unwrap_or_else(|| "(none)".to_string()),
+            get_top_folder(&record),
+            get_format(&record.filename),
+        ];
+        for (group, name) in groups.iter_mut().zip(names) {
+            let row = group.entry(name).or_default();
+            row.records += 1;
+            match size {
+                FileSize::Known(bytes) => row.bytes += bytes,
+                FileSize::Unknown => {}
+                FileSize::Missing => row.missing += 1,
+            }
+        }
+        Ok(())
+    })?;
+
+    for (title, group) in titles.into_iter().zip(groups) {
+        let mut rows: Vec<Row> = group.into_iter().map(|(name, row)| Row { name, ..row }).collect();
+        rows.sort_by(|a, b| b.records.cmp(&a.records).then_with(|| a.name.cmp(&b.name)));
+        summary.breakdowns.push((title, rows));
+    }
+    Ok(summary)
+}
+
+// The size column when the database has one, otherwise the size of the file on disk.
+// A file that isn't on disk anymore frees nothing, whatever the database says.
+fn get_file_size(record: &FileRecord, check_files: bool) -> FileSize {
+    if let (Some(bytes), false) = (record.size, check_files) {
+        return FileSize::Known(bytes);
+    }
+    let metadata = match &record.filepath {
+        Some(filepath) => match fs::metadata(filepath) {
+            Ok(metadata) => Some(metadata),
+            Err(_) => return FileSize::Missing,
+        },
+        None => None,
+    };
+    match record.size.or_else(|| metadata.map(|metadata| metadata.len())) {
+        Some(bytes) => FileSize::Known(bytes),
+        None => FileSize::Unknown,
+    }
+}
+
+fn get_top_folder(record: &FileRecord) -> String {
+    let folder = match (&record.pathname, &record.filepath) {
+        (Some(pathname), _) => PathBuf::from(pathname),
+        (None, Some(filepath)) => Path::new(filepath).parent().map(Path::to_path_buf).unwrap_or_default(),
+        (None, None) => return "(none)".to_string(),
+    };
+    let mut top = PathBuf::new();
+    let mut depth = 0;
+    for component in folder.components() {
+        if let Component::Normal(_) = component {
+            if depth == TOP_FOLDER_DEPTH {
+                break;
+            }
+            depth += 1;
+        }
+        top.push(component);
+    }
+    top.display().to_string()
+}
+
+fn get_format(filename: &str) -> String {
+    match Path::new(filename).extension() {
+        Some(extension) => extension.to_string_lossy().to_lowercase(),
+        None => "(none)".to_string(),
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    let units = ["bytes", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < units.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, units[0])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+impl Summary {
+    pub fn print(&self, verbose: bool) {
+        print!("{} records marked, {} reclaimable", self.records, format_size(self.bytes));
+        if self.unknown_size > 0 {
+            print!(" ({} records with unknown size)", self.unknown_size);
+        }
+        println!();
+        if !self.files_checked {
+            println!("Files with a size in the database weren't checked on disk (--no-file-check), missing ones among them aren't counted");
+        }
+        if !self.missing.is_empty() {
+            println!("{} marked records point at files missing on disk", self.missing.len());
+            if verbose {
+                for (id, filepath) in &self.missing {
+                    println!("    Missing ID: {}, {}", id, filepath);
+                }
+            }
+        }
+
+        for (title, rows) in &self.breakdowns {
+            println!("By {}:", title);
+            let shown = if verbose { rows.len() } else { TABLE_ROWS };
+            for row in rows.iter().take(shown) {
+                print!("    {:<40} {:>8} records {:>12}", row.name, row.records, format_size(row.bytes));
+                if row.missing > 0 {
+                    print!("  ({} missing)", row.missing);
+                }
+                println!();
+            }
+            if rows.len() > shown {
+                println!("    ... and {} more (use -v to list them all)", rows.len() - shown);
+            }
+        }
+    }
+
+    pub fn write_json(&self, path: &str, database: &str, total_records: usize) -> Result<(), Box<dyn Error>> {
+        let mut json = String::from("{\n");
+        json.push_str(&format!("  \"database\": {},\n", json_string(database)));
+        json.push_str(&format!("  \"total_records\": {},\n", total_records));
+        json.push_str(&format!("  \"marked_records\": {},\n", self.records));
+        json.push_str(&format!("  \"reclaimable_bytes\": {},\n", self.bytes));
+        json.push_str(&format!("  \"unknown_size_records\": {},\n", self.unknown_size));
+        json.push_str(&format!("  \"files_checked\": {},\n", self.files_checked));
+        json.push_str(&format!("  \"missing_records\": {},\n", self.missing.len()));
+        json.push_str("  \"missing_files\": [\n");
+        for (index, (id, filepath)) in self.missing.iter().enumerate() {
+            let separator = if index + 1 < self.missing.len() { "," } else { "" };
+            json.push_str(&format!("    {{\"id\": {}, \"filepath\": {}}}{}\n", id, json_string(filepath), separator));
+        }
+        json.push_str("  ],\n");
+        json.push_str("  \"breakdown\": {\n");
+        for (index, (title, rows)) in self.breakdowns.iter().enumerate() {
+            json.push_str(&format!("    {}: [\n", json_string(title)));
+            for (row_index, row) in rows.iter().enumerate() {
+                let separator = if row_index + 1 < rows.len() { "," } else { "" };
+                json.push_str(&format!(
+                    "      {{\"name\": {}, \"records\": {}, \"bytes\": {}, \"missing\": {}}}{}\n",
+                    json_string(&row.name), row.records, row.bytes, row.missing, separator
+                ));
+            }
+            let separator = if index + 1 < self.breakdowns.len() { "," } else { "" };
+            json.push_str(&format!("    ]{}\n", separator));
+        }
+        json.push_str("  }\n}\n");
+
+        File::create(path)?.write_all(json.as_bytes())?;
+        Ok(())
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
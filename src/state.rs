@@ -0,0 +1,121 @@
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::RowIdSet;
+
+// Checkpoints a run in a small database next to the target so an interrupted run can pick up
+// where it stopped with --resume.  Each finished search stores the records it marked, and every
+// committed deletion batch is recorded so a resumed run only deletes what is left.
+pub struct WorkState {
+    conn: Connection,
+    path: String,
+}
+
+impl WorkState {
+    pub fn open(db_path: &str, resume: bool) -> Result<WorkState> {
+        let path = format!("{}.smdupe-state", db_path);
+        if Path::new(&path).exists() {
+            if resume {
+                println!("Resuming unfinished run from {}", path);
+            } else {
+                println!("Discarding unfinished run state {} (use --resume to continue it)", path);
+                fs::remove_file(&path).map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e)))?;
+            }
+        } else if resume {
+            println!("No unfinished run found for {}, starting a new one", db_path);
+        }
+
+        let conn = Connection::open(&path)?;
+        conn.execute_batch(
+            "
+            CREATE TABLE IF NOT EXISTS run (key TEXT PRIMARY KEY, value TEXT);
+            CREATE TABLE IF NOT EXISTS passes (name TEXT PRIMARY KEY);
+            CREATE TABLE IF NOT EXISTS marked (id INTEGER PRIMARY KEY, reason TEXT, deleted INTEGER NOT NULL DEFAULT 0);
+            CREATE TABLE IF NOT EXISTS pass_records (pass TEXT, id INTEGER, PRIMARY KEY (pass, id)) WITHOUT ROWID;
+            CREATE TABLE IF NOT EXISTS keepers (id INTEGER PRIMARY KEY, keeper INTEGER);
+            "
+        )?;
+        Ok(WorkState { conn, path })
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        self.conn.query_row("SELECT value FROM run WHERE key = ?", [key], |row| row.get(0)).optional()
+    }
+
+    pub fn set(&self, key: &str, value: &str) -> Result<()> {
+        self.conn.execute("INSERT OR REPLACE INTO run (key, value) VALUES (?, ?)", [key, value])?;
+        Ok(())
+    }
+
+    pub fn pass_completed(&self, name: &str) -> Result<bool> {
+        let found: Option<String> = self.conn.query_row("SELECT name FROM passes WHERE name = ?", [name], |row| row.get(0)).optional()?;
+        Ok(found.is_some())
+    }
+
+    // Restores the records a finished search marked.  The keepers it chose stay in the state and are found with keeper().
+    pub fn load_pass(&self, name: &str) -> Result<RowIdSet> {
+        let mut stmt = self.conn.prepare("SELECT id FROM pass_records WHERE pass = ?")?;
+        let mut rows = stmt.query([name])?;
+        let mut records = RowIdSet::new();
+        while let Some(row) = rows.next()? {
+            records.insert(row.get(0)?);
+        }
+        Ok(records)
+    }
+
+    // The record kept in place of a removed duplicate, a later search's choice wins
+    pub fn keeper(&self, id: usize) -> Result<Option<usize>> {
+        self.conn.query_row("SELECT keeper FROM keepers WHERE id = ?", [id], |row| row.get(0)).optional()
+    }
+
+    // A record already marked by an earlier search keeps that search's reason
+    pub fn save_pass(&mut self, name: &str, records: &RowIdSet, reason: &str, keepers: &HashMap<usize, usize>) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut insert_marked = tx.prepare("INSERT OR IGNORE INTO marked (id, reason) VALUES (?, ?)")?;
+            let mut insert_pass = tx.prepare("INSERT OR IGNORE INTO pass_records (pass, id) VALUES (?, ?)")?;
+            let mut insert_keeper = tx.prepare("INSERT OR REPLACE INTO keepers (id, keeper) VALUES (?, ?)")?;
+            for id in records.iter() {
+                insert_marked.execute(params![id, reason])?;
+                insert_pass.execute(params![name, id])?;
+                if let Some(keeper) = keepers.get(&id) {
+                    insert_keeper.execute(params![id, keeper])?;
+                }
+            }
+            tx.execute("INSERT OR IGNORE INTO passes (name) VALUES (?)", [name])?;
+        }
+        tx.commit()
+    }
+
+    // Leaves out the records an earlier run already deleted
+    pub fn remaining(&self, records: &RowIdSet) -> Result<RowIdSet> {
+        let mut remaining = records.clone();
+        let mut stmt = self.conn.prepare("SELECT id FROM marked WHERE deleted = 1")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            remaining.remove(row.get(0)?);
+        }
+        Ok(remaining)
+    }
+
+    pub fn mark_deleted(&mut self, ids: &[usize]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut update = tx.prepare("UPDATE marked SET deleted = 1 WHERE id = ?")?;
+            for id in ids {
+                update.execute([id])?;
+            }
+        }
+        tx.commit()
+    }
+
+    // The run finished, so there is nothing left to resume
+    pub fn finish(self) -> Result<()> {
+        let path = self.path.clone();
+        self.conn.close().map_err(|(_, e)| e)?;
+        fs::remove_file(&path).map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e)))?;
+        Ok(())
+    }
+}